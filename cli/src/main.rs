@@ -1,7 +1,7 @@
 use std::str::FromStr;
 
-use clap::{Arg, Command};
-use csv2sfz::generate_sfz;
+use clap::{Arg, ArgAction, Command};
+use csv2sfz::{Options, generate_sfz_with_options};
 
 fn main() {
     let matches = Command::new("csv2sfz-cli")
@@ -15,12 +15,72 @@ fn main() {
                 .num_args(1)
                 .index(1),
         )
+        .arg(
+            Arg::new("seed")
+                .help("Project seed of the random functions rand, randint and gauss.")
+                .long("seed")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("strict")
+                .help("Abort on invalid expressions instead of keeping their original text.")
+                .long("strict")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("warn-overrides")
+                .help("Report cells overriding a non-empty value of an upper row in a merge range.")
+                .long("warn-overrides")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("comments")
+                .help("Write the source CSV file and the source line of each merge range as comments.")
+                .long("comments")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("group-opcodes")
+                .help("Move the opcodes shared by every region of a merge range into a <group> header.")
+                .long("group-opcodes")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("default-path")
+                .help("Write the common directory of the sample paths as default_path in a <control> header.")
+                .long("default-path")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("define")
+                .help("Define a project-wide variable, e.g. --define tuning=-12.")
+                .long("define")
+                .short('D')
+                .value_name("NAME=VALUE")
+                .value_parser(parse_define)
+                .action(ArgAction::Append),
+        )
         .get_matches();
 
     
     let path = matches.get_one::<String>("path").unwrap();
     let c_path = std::ffi::CString::from_str(path).unwrap();
-    let e = unsafe { generate_sfz(c_path.as_ptr()) };
+    let options = Options {
+        seed: *matches.get_one::<u64>("seed").unwrap(),
+        strict: matches.get_flag("strict"),
+        warn_overrides: matches.get_flag("warn-overrides"),
+        comments: matches.get_flag("comments"),
+        group_opcodes: matches.get_flag("group-opcodes"),
+        default_path: matches.get_flag("default-path"),
+        defines: matches
+            .get_many::<(String, String)>("define")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        ..Default::default()
+    };
+    let e = unsafe { generate_sfz_with_options(c_path.as_ptr(), &options) };
 
     match e {
         0 => println!("Execution succeeded"),
@@ -29,7 +89,14 @@ fn main() {
         -3 => println!("Error occurred while parsing CSV files"),
         -4 => println!("Error occurred while processing CSV expressions"),
         -5 => println!("Error occurred while saving sfz files to disk"),
+        -6 => println!("Error occurred while loading project definitions"),
         _ => println!("Unknown error"),
     }
 
 }
+
+fn parse_define(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got `{}`", s))
+}
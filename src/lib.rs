@@ -6,20 +6,88 @@ use rayon::prelude::*;
 use regex::Regex;
 use std::{
     collections::HashMap,
-    ffi::{CStr, c_char, c_int},
+    ffi::{CStr, c_char, c_int, c_void},
     fs::{self},
     io::Write,
     ops::{Not, Range},
-    path::Path,
-    sync::LazyLock,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{Arc, LazyLock},
 };
 
+/// Options shared by every CSV file of a conversion run.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Project seed of the random functions `rand()`, `randint()` and `gauss()`. Each cell combines
+    /// it with the sample path and the column, so regenerating yields identical SFZ files.
+    pub seed: u64,
+    /// Abort the conversion on invalid expressions instead of keeping their original text.
+    pub strict: bool,
+    /// Project-wide variables as `(name, expression)`, overriding those of `csv2sfz.conf` and
+    /// `@define` sheets.
+    pub defines: Vec<(String, String)>,
+    /// Functions of the host application, callable in expressions like the built-in ones.
+    pub functions: Vec<HostFunction>,
+    /// Report every cell where a lower row of a merge range overrides a non-empty value of an
+    /// upper row.
+    pub warn_overrides: bool,
+    /// Write a banner naming the CSV file and the version at the top of each SFZ file, and the
+    /// source line of each merge range before its regions.
+    pub comments: bool,
+    /// Move the opcodes shared by every region of a merge range into a `<group>` header before
+    /// them. Sheets with their own `<group>` headers are left untouched.
+    pub group_opcodes: bool,
+    /// Write the common directory of the sample paths of each SFZ file as `default_path` in a
+    /// `<control>` header, and the paths relative to it.
+    pub default_path: bool,
+}
+
+impl Options {
+    /// Register a function callable in expressions as `name(...)`. Errors are reported as
+    /// invalid expressions. It may be called from several threads at once.
+    pub fn add_function<F>(&mut self, name: impl Into<String>, func: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.functions.push(HostFunction {
+            name: name.into(),
+            func: Arc::new(func),
+        });
+        self
+    }
+}
+
+/// An argument or the result of a [`HostFunction`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    /// A quoted string literal, a string file name parameter or a non-numeric table value.
+    String(String),
+}
+
+pub type HostFn = dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync;
+
+/// A function registered with [`Options::add_function`].
+#[derive(Clone)]
+pub struct HostFunction {
+    pub name: String,
+    pub func: Arc<HostFn>,
+}
+
+impl std::fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostFunction")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
 /// Recursively convert any CSV file in the directory to SFZ.
 ///
 /// # Arguments
 ///
 /// * `dir_path` - A null-terminated C string representing the path to the directory containing
-///                  samples and CSV files.
+///   samples and CSV files.
 ///
 /// # Returns
 ///
@@ -29,6 +97,7 @@ use std::{
 /// * `-3` - Error occurred while parsing CSV files.
 /// * `-4` - Error occurred while processing CSV expressions.
 /// * `-5` - Error occurred while saving sfz files to disk.
+/// * `-6` - Error occurred while loading project definitions.
 ///
 /// # Safety
 ///
@@ -36,9 +105,35 @@ use std::{
 /// the provided `dir_path` pointer is non-null and points to a valid, null-terminated C string.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn generate_sfz(dir_path: *const c_char) -> c_int {
+    unsafe { generate_sfz_with_options(dir_path, std::ptr::null()) }
+}
+
+/// Same as [`generate_sfz`], but with the given conversion options.
+///
+/// # Arguments
+///
+/// * `dir_path` - A null-terminated C string representing the path to the directory containing
+///   samples and CSV files.
+/// * `options` - Options created by [`csv2sfz_options_new`], or null for the default options.
+///
+/// # Returns
+///
+/// The same codes as [`generate_sfz`].
+///
+/// # Safety
+///
+/// The caller must ensure that `dir_path` is non-null and points to a valid, null-terminated C
+/// string, and that `options` is either null or a valid pointer to [`Options`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn generate_sfz_with_options(
+    dir_path: *const c_char,
+    options: *const Options,
+) -> c_int {
     let Some(path) = try_get_dir_path(dir_path) else {
         return -1;
     };
+    let options = unsafe { options.as_ref() }.cloned().unwrap_or_default();
+    let _strings = math_expr::StringScope::enter();
 
     let mut sample_paths = Vec::new();
     let mut meta_paths = Vec::new();
@@ -46,6 +141,10 @@ pub unsafe extern "C" fn generate_sfz(dir_path: *const c_char) -> c_int {
         return -2;
     }
 
+    let Ok((project, meta_paths)) = load_project(path, meta_paths, options) else {
+        return -6;
+    };
+
     let rows_vars = sample_paths
         .iter()
         .filter_map(|sample_path| {
@@ -55,13 +154,14 @@ pub unsafe extern "C" fn generate_sfz(dir_path: *const c_char) -> c_int {
             Some((path.to_str()?, sample))
         })
         .collect::<HashMap<&str, HashMap<&str, &str>>>();
+    warn_shadowed_globals(&project, &rows_vars);
 
     let result = meta_paths.par_iter().try_for_each(|meta_path| {
         let csv_path = Path::new(meta_path);
         let Ok(mut sample_csv) = parse_sample_csv(csv_path) else {
             return Err(-3);
         };
-        if expand_sample_csv(&mut sample_csv, &sample_paths, &rows_vars).is_err() {
+        if expand_sample_csv(&mut sample_csv, &sample_paths, &rows_vars, &project).is_err() {
             return Err(-4);
         };
 
@@ -78,6 +178,207 @@ pub unsafe extern "C" fn generate_sfz(dir_path: *const c_char) -> c_int {
     }
 }
 
+/// Create default conversion options. The returned pointer must be released with
+/// [`csv2sfz_options_free`].
+#[unsafe(no_mangle)]
+pub extern "C" fn csv2sfz_options_new() -> *mut Options {
+    Box::into_raw(Box::default())
+}
+
+/// Release options created by [`csv2sfz_options_new`].
+///
+/// # Safety
+///
+/// `options` must be null or a pointer returned by [`csv2sfz_options_new`] that has not been
+/// released yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_free(options: *mut Options) {
+    if !options.is_null() {
+        drop(unsafe { Box::from_raw(options) });
+    }
+}
+
+/// Set the project seed of the random functions.
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_set_seed(options: *mut Options, seed: u64) {
+    if let Some(options) = unsafe { options.as_mut() } {
+        options.seed = seed;
+    }
+}
+
+/// Define a project-wide variable, overriding those of `csv2sfz.conf` and `@define` sheets.
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`]. `name` and
+/// `value` must be null or valid, null-terminated C strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_define(
+    options: *mut Options,
+    name: *const c_char,
+    value: *const c_char,
+) {
+    let to_string = |s: *const c_char| {
+        (!s.is_null())
+            .then(|| unsafe { CStr::from_ptr(s) }.to_str().ok())
+            .flatten()
+            .map(str::to_string)
+    };
+    if let (Some(options), Some(name), Some(value)) = (
+        unsafe { options.as_mut() },
+        to_string(name),
+        to_string(value),
+    ) {
+        options.defines.push((name, value));
+    }
+}
+
+/// Set whether invalid expressions abort the conversion (non-zero) or keep their original text
+/// (zero, default). They are reported on stderr in both cases.
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_set_strict(options: *mut Options, strict: c_int) {
+    if let Some(options) = unsafe { options.as_mut() } {
+        options.strict = strict != 0;
+    }
+}
+
+/// Set whether cells overriding a non-empty value of an upper row in a merge range are reported
+/// on stderr (non-zero) or not (zero, default).
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_set_warn_overrides(options: *mut Options, warn: c_int) {
+    if let Some(options) = unsafe { options.as_mut() } {
+        options.warn_overrides = warn != 0;
+    }
+}
+
+/// Set whether SFZ files start with a banner and each merge range with a comment naming its
+/// source line (non-zero) or not (zero, default).
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_set_comments(options: *mut Options, comments: c_int) {
+    if let Some(options) = unsafe { options.as_mut() } {
+        options.comments = comments != 0;
+    }
+}
+
+/// Set whether the opcodes shared by every region of a merge range are moved into a `<group>`
+/// header before them (non-zero) or not (zero, default).
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_set_group_opcodes(options: *mut Options, group: c_int) {
+    if let Some(options) = unsafe { options.as_mut() } {
+        options.group_opcodes = group != 0;
+    }
+}
+
+/// Set whether the common directory of the sample paths is written as `default_path` in a
+/// `<control>` header (non-zero) or not (zero, default).
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_set_default_path(options: *mut Options, default: c_int) {
+    if let Some(options) = unsafe { options.as_mut() } {
+        options.default_path = default != 0;
+    }
+}
+
+/// A function of the host application, see [`csv2sfz_options_add_function`].
+///
+/// `numbers` and `strings` both hold `argc` elements. A string argument is a null-terminated C
+/// string in `strings` with `NaN` in `numbers`, a number has a null pointer in `strings`. On
+/// success the function writes `result` and returns `0`, any other value is reported as an
+/// invalid expression. The pointers are only valid during the call.
+pub type Csv2sfzFunction = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    argc: usize,
+    numbers: *const f64,
+    strings: *const *const c_char,
+    result: *mut f64,
+) -> c_int;
+
+/// Register a function callable in expressions as `name(...)`.
+///
+/// # Safety
+///
+/// `options` must be null or a valid pointer returned by [`csv2sfz_options_new`]. `name` must be
+/// null or a valid, null-terminated C string. `func` is called from several threads at once with
+/// `user_data`, both must stay valid until the conversions using `options` return.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn csv2sfz_options_add_function(
+    options: *mut Options,
+    name: *const c_char,
+    func: Option<Csv2sfzFunction>,
+    user_data: *mut c_void,
+) {
+    struct UserData(*mut c_void);
+    // The caller guarantees that `func` may be called with `user_data` from any thread.
+    unsafe impl Send for UserData {}
+    unsafe impl Sync for UserData {}
+
+    let name = (!name.is_null())
+        .then(|| unsafe { CStr::from_ptr(name) }.to_str().ok())
+        .flatten();
+    let (Some(options), Some(name), Some(func)) = (unsafe { options.as_mut() }, name, func) else {
+        return;
+    };
+    let user_data = UserData(user_data);
+    options.add_function(name, move |args| {
+        let strings = args
+            .iter()
+            .map(|arg| match arg {
+                Value::Number(_) => Ok(None),
+                Value::String(s) => std::ffi::CString::new(s.as_str()).map(Some),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let numbers = args
+            .iter()
+            .map(|arg| match arg {
+                Value::Number(v) => *v,
+                Value::String(_) => f64::NAN,
+            })
+            .collect::<Vec<_>>();
+        let string_ptrs = strings
+            .iter()
+            .map(|s| s.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()))
+            .collect::<Vec<_>>();
+        let mut result = 0.0;
+        let user_data = &user_data;
+        match unsafe {
+            func(
+                user_data.0,
+                args.len(),
+                numbers.as_ptr(),
+                string_ptrs.as_ptr(),
+                &mut result,
+            )
+        } {
+            0 => Ok(Value::Number(result)),
+            code => Err(format!("Failed with code {}", code)),
+        }
+    });
+}
+
 fn try_get_dir_path<'a>(dir_path: *const c_char) -> Option<&'a Path> {
     if dir_path.is_null() {
         return None;
@@ -112,6 +413,497 @@ enum Error {
     CSVHeader,
     #[from]
     Glob(globset::Error),
+    Expr(Vec<ExprError>),
+    Config(usize),
+}
+
+/// An expression of a cell that could not be parsed or evaluated.
+#[derive(Debug)]
+struct ExprError {
+    expr: String,
+    message: String,
+}
+
+/// Data shared by every CSV file of a conversion run.
+#[derive(Debug, Default)]
+struct Project {
+    options: Options,
+    /// Directory being converted, CSV files are identified relative to it.
+    root: PathBuf,
+    /// Variables available in every expression, see [`load_globals`].
+    globals: IndexMap<String, f64>,
+    /// Sheets marked with `@table(name)`, read by `lookup()`.
+    tables: Arc<HashMap<String, Table>>,
+    /// User-defined functions, see [`load_functions`].
+    functions: Vec<Arc<Function>>,
+    /// Invalid expressions already reported as `(file, column, line, expression)`, since a cell is
+    /// evaluated once per matched sample.
+    reported: std::sync::Mutex<std::collections::HashSet<(PathBuf, usize, usize, String)>>,
+}
+
+impl Project {
+    /// `path` relative to the root with `/` separators, which doesn't depend on how the root was
+    /// given nor on the platform.
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .components()
+            .filter(|c| !matches!(c, std::path::Component::CurDir))
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// An auxiliary sheet mapping the keys of its first column to the values of the other columns.
+#[derive(Debug, Default)]
+struct Table {
+    /// Titles of the value columns.
+    columns: Vec<String>,
+    rows: IndexMap<String, Vec<String>>,
+}
+
+impl Table {
+    fn get(&self, key: f64, column: f64) -> Result<f64, String> {
+        let key_str = math_expr::as_string(key);
+        let row = self
+            .rows
+            .iter()
+            .find(|(k, _)| match &key_str {
+                Some(key) => *k == key,
+                None => k.parse::<f64>().is_ok_and(|k| k == key),
+            })
+            .map(|(_, row)| row)
+            .ok_or_else(|| format!("No row `{}`", key_str.unwrap_or(key.to_string())))?;
+        let idx = match math_expr::as_string(column) {
+            Some(name) => self
+                .columns
+                .iter()
+                .position(|c| *c == name)
+                .ok_or_else(|| format!("No column `{}`", name))?,
+            // 1-based, as the key column comes first.
+            None if column >= 1.0 && column.fract() == 0.0 => column as usize - 1,
+            None => return Err(format!("No column {}", column)),
+        };
+        match row.get(idx).map(|v| v.trim()) {
+            Some(v) if !v.is_empty() => Ok(math_expr::value_of(v)),
+            _ => Err(format!(
+                "Empty cell of column `{}`",
+                self.columns
+                    .get(idx)
+                    .map_or(column.to_string(), |c| c.clone())
+            )),
+        }
+    }
+}
+
+const CONFIG_FILE: &str = "csv2sfz.conf";
+
+/// An entry of `csv2sfz.conf`, which consists of `[section]` headers, `name = value` lines and
+/// `#` comments.
+#[derive(Debug)]
+struct ConfigEntry {
+    section: String,
+    name: String,
+    value: String,
+    line: usize,
+}
+
+fn parse_config(text: &str) -> Result<Vec<ConfigEntry>, Error> {
+    let mut section = String::new();
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        let (name, value) = line.split_once('=').ok_or(Error::Config(i + 1))?;
+        entries.push(ConfigEntry {
+            section: section.clone(),
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+            line: i + 1,
+        });
+    }
+    Ok(entries)
+}
+
+/// Read the first cell of a CSV file, which marks auxiliary sheets such as `@define`, or `None`
+/// for an empty file.
+fn first_title(path: &Path) -> Result<Option<String>, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+    let Some(record) = reader.records().next().transpose()? else {
+        return Ok(None);
+    };
+    Ok(Some(record.get(0).unwrap_or_default().trim().to_string()))
+}
+
+/// Read the rows of an auxiliary sheet, including its title row.
+fn read_sheet(path: &Path) -> Result<Vec<Vec<String>>, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+    reader
+        .records()
+        .map(|record| Ok(record?.iter().map(|s| s.to_string()).collect()))
+        .collect()
+}
+
+/// A project-wide variable definition, `source` is where it is declared for messages.
+#[derive(Debug)]
+struct Define {
+    source: String,
+    name: String,
+    value: String,
+}
+
+/// Rows of a `@define` or `@function` sheet as definitions, the first column holding the names and
+/// the second the values. Rows without a name are skipped.
+fn read_defines(path: &Path) -> Result<Vec<Define>, Error> {
+    let defines = read_sheet(path)?
+        .into_iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(i, row)| {
+            let mut cells = row.into_iter();
+            let name = cells.next()?.trim().to_string();
+            let value = cells.next().unwrap_or_default().trim().to_string();
+            (!name.is_empty()).then(|| Define {
+                source: format!("{}:{}", path.display(), cell_name(0, i)),
+                name,
+                value,
+            })
+        })
+        .collect();
+    Ok(defines)
+}
+
+/// Load `csv2sfz.conf` and the auxiliary sheets of the project, returning the remaining CSV files
+/// to convert.
+fn load_project(
+    root_path: &Path,
+    meta_paths: Vec<String>,
+    options: Options,
+) -> Result<(Project, Vec<String>), Error> {
+    let mut sample_csv_paths = Vec::new();
+    let mut defines = Vec::new();
+    let mut tables = HashMap::new();
+    let mut functions = Vec::new();
+    // Sheets are read in the order of their paths, so that a later `@define` sheet overrides the
+    // earlier ones the same way on every filesystem.
+    let mut meta_paths = meta_paths;
+    meta_paths.sort_unstable();
+    for meta_path in meta_paths {
+        let path = Path::new(&meta_path);
+        let Some(title) = first_title(path)? else {
+            continue;
+        };
+        match parse_annotation(&title).as_deref() {
+            Some(["define", ..]) => defines.extend(read_defines(path)?),
+            Some(["function", ..]) => functions.extend(read_defines(path)?),
+            Some(["table", name, ..]) if !name.is_empty() => {
+                let mut rows = read_sheet(path)?.into_iter();
+                let columns = rows.next().unwrap_or_default();
+                let mut table = Table {
+                    columns: columns
+                        .iter()
+                        .skip(1)
+                        .map(|c| c.trim().to_string())
+                        .collect(),
+                    rows: IndexMap::new(),
+                };
+                for (i, row) in rows.enumerate() {
+                    let Some((key, values)) = row.split_first() else {
+                        continue;
+                    };
+                    let key = key.trim();
+                    if key.is_empty() {
+                        continue;
+                    }
+                    if table
+                        .rows
+                        .insert(key.to_string(), values.to_vec())
+                        .is_some()
+                    {
+                        eprintln!(
+                            "warning: {}:{}: duplicate key `{}` of table `{}`",
+                            path.display(),
+                            cell_name(0, i + 1),
+                            key,
+                            name
+                        );
+                    }
+                }
+                if tables.insert(name.to_string(), table).is_some() {
+                    eprintln!(
+                        "warning: {}: table `{}` is defined more than once",
+                        path.display(),
+                        name
+                    );
+                }
+            }
+            Some(["table", ..]) => {
+                let source = format!("{}:{}", path.display(), cell_name(0, 0));
+                report_error(&source, "missing table name, e.g. `@table(dyn)`", &options)?;
+            }
+            _ => sample_csv_paths.push(meta_path),
+        }
+    }
+
+    let config_path = root_path.join(CONFIG_FILE);
+    let config = match config_path.is_file() {
+        true => parse_config(&fs::read_to_string(&config_path)?).inspect_err(|e| {
+            if let Error::Config(line) = e {
+                eprintln!(
+                    "error: {}:{}: expected `name = value`",
+                    config_path.display(),
+                    line
+                )
+            }
+        })?,
+        false => Vec::new(),
+    };
+    for entry in config {
+        let define = Define {
+            source: format!("{}:{}", config_path.display(), entry.line),
+            name: entry.name,
+            value: entry.value,
+        };
+        match entry.section.as_str() {
+            "define" => defines.push(define),
+            "function" => functions.push(define),
+            section => eprintln!(
+                "warning: {}:{}: unknown section `[{}]`",
+                config_path.display(),
+                entry.line,
+                section
+            ),
+        }
+    }
+
+    defines.extend(options.defines.iter().map(|(name, value)| Define {
+        source: String::from("--define"),
+        name: name.trim().to_string(),
+        value: value.trim().to_string(),
+    }));
+
+    let mut project = Project {
+        options,
+        root: root_path.to_path_buf(),
+        globals: IndexMap::new(),
+        tables: Arc::new(tables),
+        functions: Vec::new(),
+        ..Default::default()
+    };
+    project.functions = load_functions(&functions, &project)?;
+    project.globals = load_globals(&defines, &project)?;
+    Ok((project, sample_csv_paths))
+}
+
+/// Evaluate project-wide variables, ordered from the lowest to the highest precedence: `@define`
+/// sheets, `csv2sfz.conf`, then [`Options::defines`]. Each value is an expression that may use
+/// the variables defined before it.
+fn load_globals(defines: &[Define], project: &Project) -> Result<IndexMap<String, f64>, Error> {
+    static RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-zA-Z_]+$").unwrap());
+    let options = &project.options;
+    let mut globals = IndexMap::<String, f64>::new();
+    let mut sources = HashMap::<&str, &str>::new();
+    for define in defines {
+        if !RE.is_match(&define.name) {
+            let message = format!(
+                "`{}` is not a valid variable name, only letters and `_` are allowed",
+                define.name
+            );
+            report_error(&define.source, &message, options)?;
+            continue;
+        }
+        let scope = Project {
+            options: options.clone(),
+            root: project.root.clone(),
+            globals: globals.clone(),
+            tables: project.tables.clone(),
+            functions: project.functions.clone(),
+            ..Default::default()
+        };
+        let ctx = map_to_ctx(&scope, None);
+        let value = math_expr::eval(&define.value, &ctx).map(|v| math_expr::value_of(&v));
+        let value = match value {
+            Ok(value) => value,
+            Err(message) => {
+                let message = format!(
+                    "invalid value `{}` of `{}`: {}",
+                    define.value, define.name, message
+                );
+                report_error(&define.source, &message, options)?;
+                continue;
+            }
+        };
+        if let Some(source) = sources.insert(&define.name, &define.source) {
+            eprintln!(
+                "warning: {}: `{}` overrides the definition at {}",
+                define.source, define.name, source
+            );
+        }
+        globals.insert(define.name.clone(), value);
+    }
+    Ok(globals)
+}
+
+/// A user-defined function such as `vel(x, n) = floor(nl(x/n)*127)`, `source` is where it is
+/// declared for messages.
+#[derive(Debug)]
+struct Function {
+    source: String,
+    name: String,
+    params: Vec<String>,
+    body: String,
+}
+
+impl Function {
+    /// Calls nested deeper than this are reported instead of overflowing the stack.
+    const MAX_DEPTH: usize = 32;
+
+    /// The body sees the parameters, the project variables and every function of `ctx`, but not
+    /// the variables of the calling cell.
+    fn call(
+        &self,
+        args: &[mexprp::Term<f64>],
+        ctx: &mexprp::Context<f64>,
+        globals: &IndexMap<String, f64>,
+    ) -> mexprp::Calculation<f64> {
+        if args.len() != self.params.len() {
+            return Err(math_expr::fail(format!(
+                "`{}` expects {} arguments, found {}",
+                self.name,
+                self.params.len(),
+                args.len()
+            )));
+        }
+        let mut body_ctx = ctx.clone();
+        body_ctx.vars = mexprp::Context::<f64>::new().vars;
+        globals.iter().for_each(|(k, v)| body_ctx.set_var(k, *v));
+        for (param, arg) in self.params.iter().zip(args) {
+            body_ctx.set_var(param, arg.eval_ctx(ctx)?);
+        }
+        math_expr::nested(Self::MAX_DEPTH, || {
+            math_expr::eval_value(&self.body, &body_ctx)
+        })
+        .unwrap_or_else(|| {
+            let message = format!("Calls are nested deeper than {}", Self::MAX_DEPTH);
+            Err(math_expr::fail(message))
+        })
+        .map_err(|e| match math_expr::describe(e) {
+            message if message.starts_with("in `") => math_expr::fail(message),
+            message => math_expr::fail(format!(
+                "in `{}` defined at {}: {}",
+                self.name, self.source, message
+            )),
+        })
+    }
+}
+
+/// Parse the declarations of user-defined functions, whose names are signatures like `vel(x, n)`
+/// and whose values are the bodies. Later declarations override earlier ones.
+fn load_functions(decls: &[Define], project: &Project) -> Result<Vec<Arc<Function>>, Error> {
+    static RE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^([a-zA-Z_][a-zA-Z0-9_]*)\s*\(\s*([a-zA-Z_]+(?:\s*,\s*[a-zA-Z_]+)*)?\s*\)$")
+            .unwrap()
+    });
+    let options = &project.options;
+    let report = |source: &str, message: String| report_error(source, &message, options);
+    let builtins = map_to_ctx(
+        &Project {
+            options: options.clone(),
+            ..Default::default()
+        },
+        None,
+    );
+    let mut functions = IndexMap::<String, Arc<Function>>::new();
+    for decl in decls {
+        let Some(caps) = RE.captures(&decl.name) else {
+            report(
+                &decl.source,
+                format!(
+                    "`{}` is not a valid function signature, expected `name(a, b)`",
+                    decl.name
+                ),
+            )?;
+            continue;
+        };
+        let name = caps[1].to_string();
+        let params = caps.get(2).map_or(Vec::new(), |p| {
+            p.as_str()
+                .split(',')
+                .map(|p| p.trim().to_string())
+                .collect()
+        });
+        if builtins.funcs.contains_key(&math_expr::mangle(&name)) {
+            eprintln!(
+                "warning: {}: `{}` overrides the built-in function",
+                decl.source, name
+            );
+        }
+        if let Some(previous) = functions.get(&name) {
+            eprintln!(
+                "warning: {}: `{}` overrides the definition at {}",
+                decl.source, name, previous.source
+            );
+        }
+        functions.insert(
+            name.clone(),
+            Arc::new(Function {
+                source: decl.source.clone(),
+                name,
+                params,
+                body: decl.value.clone(),
+            }),
+        );
+    }
+
+    // Check the syntax of the bodies once every function is known to the parser.
+    let scope = Project {
+        options: options.clone(),
+        functions: functions.values().cloned().collect(),
+        ..Default::default()
+    };
+    let ctx = map_to_ctx(&scope, None);
+    for function in scope.functions {
+        if let Err(message) = math_expr::parse(&function.body, &ctx) {
+            report(
+                &function.source,
+                format!(
+                    "invalid body `{}` of `{}`: {}",
+                    function.body, function.name, message
+                ),
+            )?;
+            functions.shift_remove(&function.name);
+        }
+    }
+    Ok(functions.into_values().collect())
+}
+
+/// File name parameters take precedence over project-wide variables, warn about each shadowed one.
+fn warn_shadowed_globals(project: &Project, rows_vars: &HashMap<&str, HashMap<&str, &str>>) {
+    project.globals.keys().for_each(|name| {
+        let count = rows_vars
+            .values()
+            .filter(|vars| vars.contains_key(name.as_str()))
+            .count();
+        if count > 0 {
+            eprintln!(
+                "warning: file name parameter `{}` of {} samples shadows the project variable `{}`",
+                name, count, name
+            );
+        }
+    });
 }
 
 // Give control to users
@@ -161,14 +953,15 @@ fn parse_opcode(param: &str) -> Option<(&str, &str)> {
         .and_then(|c| Some((c.get(1)?.as_str(), c.get(2)?.as_str())))
 }
 
+/// File name parameters of a sample name. Besides numeric parameters such as `k60`, a parameter
+/// written as `name=value`, such as `dyn=mf`, holds a string that can be a key of lookup tables.
 fn parse_sample_name(name: &str) -> HashMap<&str, &str> {
     name.split('_')
         .filter_map(|param| {
             if param.is_empty() {
                 return None;
             };
-            // param.split_once('=').or_else(|| parse_opcode(param))
-            parse_opcode(param)
+            param.split_once('=').or_else(|| parse_opcode(param))
         })
         .collect()
 }
@@ -198,8 +991,11 @@ fn parse_annotation(input: &str) -> Option<Vec<&str>> {
 
 #[derive(Debug, Clone)]
 struct SampleCSV {
+    path: PathBuf,
     opcode_indices: IndexMap<String, usize>, // Used for output
     anno_indices: HashMap<String, usize>,    // Used for find annotations
+    let_indices: IndexMap<String, usize>,    // Variables computed per row
+    titles: Vec<String>,                     // Used for annotation parameters
     header_ranges: Vec<Range<usize>>,
     rows: Vec<Vec<String>>,
 }
@@ -218,7 +1014,14 @@ fn parse_sample_csv(path: impl AsRef<Path>) -> Result<SampleCSV, Error> {
 
     let mut anno_indices = HashMap::new();
     let mut opcode_indices = IndexMap::new();
-    create_indices(opcodes, &mut opcode_indices, &mut anno_indices);
+    let mut let_indices = IndexMap::new();
+    let titles: Vec<String> = opcodes.iter().map(|s| s.to_string()).collect();
+    create_indices(
+        opcodes,
+        &mut opcode_indices,
+        &mut anno_indices,
+        &mut let_indices,
+    );
 
     let rows: Vec<Vec<String>> = records
         .filter_map(|record| {
@@ -230,46 +1033,125 @@ fn parse_sample_csv(path: impl AsRef<Path>) -> Result<SampleCSV, Error> {
     let header_idx = anno_indices.get("header").ok_or(Error::CSVHeader)?;
     let mut header_ranges = Vec::new();
     creat_header_ranges(&rows, &mut header_ranges, header_idx);
+    let mut rows = rows;
+    let notes = titles_of(&titles, "note");
+    rows.iter_mut().for_each(|row| {
+        notes.iter().for_each(|idx| {
+            if let Some(cell) = row.get_mut(*idx) {
+                cell.clear();
+            }
+        })
+    });
 
     Ok(SampleCSV {
+        path: path.as_ref().to_path_buf(),
         opcode_indices,
         anno_indices,
+        let_indices,
+        titles,
         header_ranges,
         rows,
     })
 }
 
+impl SampleCSV {
+    /// Parameters of the annotation `name`, if a column is annotated with it.
+    fn anno_params(&self, name: &str) -> Option<(usize, Vec<&str>)> {
+        let idx = *self.anno_indices.get(name)?;
+        let params = parse_annotation(self.titles.get(idx)?)?;
+        Some((idx, params[1..].to_vec()))
+    }
+
+    /// Index of the opcode column `name`, appending an empty column to every row if it is absent.
+    fn opcode_column(&mut self, name: &str) -> usize {
+        if let Some(idx) = self.opcode_indices.get(name) {
+            return *idx;
+        }
+        let idx = self
+            .titles
+            .len()
+            .max(self.rows.iter().map(Vec::len).max().unwrap_or(0));
+        self.titles.resize(idx, String::new());
+        self.titles.push(name.to_string());
+        self.rows
+            .iter_mut()
+            .for_each(|row| row.resize(idx + 1, String::new()));
+        self.opcode_indices.insert(name.to_string(), idx);
+        idx
+    }
+}
+
+/// Columns annotated with `@let` or `@foreach` are variables of their row, columns annotated with
+/// `@keyspread`, `@velspread`, `@rr`, `@release`, `@key`, `@where`, `@name`, `@extends`,
+/// `@enabled` or `@comment` are inputs of post-processing and `@note` columns are documentation,
+/// neither is added to `opcode_indices`.
 fn create_indices(
     opcodes: csv::StringRecord,
     opcode_indices: &mut IndexMap<String, usize>,
     anno_indices: &mut HashMap<String, usize>,
+    let_indices: &mut IndexMap<String, usize>,
 ) {
     *opcode_indices = opcodes
         .iter()
         .enumerate()
-        .map(|(i, a)| {
+        .filter_map(|(i, a)| {
             let a = parse_annotation(a)
                 .map(|anno| match anno[0] {
                     "raw" => {
                         anno_indices.insert(anno[0].to_string(), i);
-                        format!("__raw_{}", i)
+                        Some(format!("__raw_{}", i))
                     }
                     "sample" => {
                         anno_indices.insert(anno[0].to_string(), i);
-                        anno.get(1).unwrap_or(&"sample").to_string()
+                        Some(anno.get(1).unwrap_or(&"sample").to_string())
                     }
                     "header" => {
                         anno_indices.insert(anno[0].to_string(), i);
-                        String::from("__header")
+                        Some(String::from("__header"))
+                    }
+                    "let" if anno.len() > 1 => {
+                        let_indices.insert(anno[1].to_string(), i);
+                        None
+                    }
+                    "foreach" | "note" => None,
+                    "keyspread" | "velspread" | "rr" | "release" | "key" | "where" | "name"
+                    | "extends" | "enabled" | "comment" => {
+                        anno_indices.insert(anno[0].to_string(), i);
+                        None
                     }
-                    _ => a.to_string(),
+                    _ => Some(a.to_string()),
                 })
-                .unwrap_or(a.to_string());
-            (a, i)
+                .unwrap_or(Some(a.to_string()))?;
+            Some((a, i))
         })
         .collect::<IndexMap<String, usize>>();
 }
 
+/// Columns annotated with `name`, which may be declared several times.
+fn titles_of(titles: &[String], name: &str) -> Vec<usize> {
+    titles
+        .iter()
+        .enumerate()
+        .filter(|(_, title)| parse_annotation(title).is_some_and(|anno| anno[0] == name))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+/// SFZ preprocessor directives, which may start the first cell of a row in a `@raw` column.
+const SFZ_DIRECTIVES: [&str; 2] = ["#define", "#include"];
+
+/// Rows whose first cell starts with `#` are commented out, unless it is an SFZ directive.
+fn is_commented(row: &[String]) -> bool {
+    row.first().is_some_and(|cell| {
+        let cell = cell.trim_start();
+        let is_directive = SFZ_DIRECTIVES.iter().any(|directive| {
+            cell.strip_prefix(directive)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        });
+        cell.starts_with('#') && !is_directive
+    })
+}
+
 fn creat_header_ranges(
     rows: &[Vec<String>],
     header_ranges: &mut Vec<Range<usize>>,
@@ -277,7 +1159,11 @@ fn creat_header_ranges(
 ) {
     let mut start = 0;
     for (i, row) in rows.iter().enumerate().skip(1) {
-        if !row[*header_idx].is_empty() {
+        let header = match *header_idx == 0 && is_commented(row) {
+            true => row[0].trim_start().trim_start_matches('#'),
+            false => row[*header_idx].as_str(),
+        };
+        if !header.is_empty() {
             header_ranges.push(start..i);
             start = i;
         }
@@ -287,42 +1173,466 @@ fn creat_header_ranges(
     }
 }
 
-fn apply_expr(cell: &mut String, ctx: Option<mexprp::Context<f64>>) -> Result<(), Error> {
-    static RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$\{([^}]+)\}").unwrap());
-    *cell = RE
-        .replace_all(cell, |caps: &regex::Captures| {
-            caps.get(1)
-                .and_then(|m| {
-                    mexprp::Expression::parse_ctx(m.as_str(), ctx.clone()?)
-                        .ok()?
-                        .eval()
-                        .and_then(math_expr::format_float)
-                        .ok()
-                })
-                .unwrap_or_default()
-        })
-        .to_string();
-    Ok(())
+/// A piece of a cell template.
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    /// Literal text, including the `${` of an escaped `$${`.
+    Text(&'a str),
+    /// An expression, `source` is the whole `${...}`.
+    Expr { expr: &'a str, source: &'a str },
+    /// A `${` without its closing brace, up to the end of the cell.
+    Unclosed(&'a str),
 }
 
-mod math_expr {
-    use mexprp::*;
-
-    pub(crate) fn format_float(val: Answer<f64>) -> Result<String, MathError> {
-        let v = match val {
-            Answer::Single(v) => v,
-            Answer::Multiple(v) => v[0],
-        };
-        Ok((if v == -0.0 { 0.0 } else { v }).to_string())
+/// Split a cell into literal text and `${...}` expressions. `$${` is an escaped literal `${`.
+/// Inside an expression, braces nest and quoted strings may contain any character.
+fn tokenize_cell(cell: &str) -> Vec<Token<'_>> {
+    let bytes = cell.as_bytes();
+    let mut tokens = Vec::new();
+    let (mut i, mut text_start) = (0, 0);
+    while i < bytes.len() {
+        let escaped = bytes[i..].starts_with(b"$${");
+        if !escaped && !bytes[i..].starts_with(b"${") {
+            i += 1;
+            continue;
+        }
+        if text_start < i {
+            tokens.push(Token::Text(&cell[text_start..i]));
+        }
+        if escaped {
+            tokens.push(Token::Text(&cell[i + 1..i + 3]));
+            i += 3;
+        } else if let Some(end) = find_closing_brace(bytes, i + 2) {
+            tokens.push(Token::Expr {
+                expr: &cell[i + 2..end],
+                source: &cell[i..=end],
+            });
+            i = end + 1;
+        } else {
+            tokens.push(Token::Unclosed(&cell[i..]));
+            i = bytes.len();
+        }
+        text_start = i;
     }
-
-    fn py_round(n: &f64, decimals: &f64) -> f64 {
-        let factor = 10f64.powi(*decimals as i32);
-        (n * factor).round() / factor
+    if text_start < bytes.len() {
+        tokens.push(Token::Text(&cell[text_start..]));
     }
+    tokens
+}
 
-    type Exp = fn(&[Term<f64>], &Context<f64>) -> Calculation<f64>;
-    pub(crate) const EXPS: [(&str, Exp); 5] = [
+fn find_closing_brace(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut i = start;
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(_), b'\\') => i += 1,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, c @ (b'"' | b'\'')) => quote = Some(c),
+            (None, b'{') => depth += 1,
+            (None, b'}') if depth == 0 => return Some(i),
+            (None, b'}') => depth -= 1,
+            (None, _) => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Evaluate every `${...}` of `cell`. Expressions that fail keep their original text and are
+/// returned as [`Error::Expr`].
+fn apply_expr(cell: &mut String, ctx: mexprp::Context<f64>) -> Result<(), Error> {
+    let mut errors = Vec::new();
+    *cell = tokenize_cell(cell)
+        .into_iter()
+        .map(|token| match token {
+            Token::Text(text) => text.to_string(),
+            Token::Expr { expr, source } => math_expr::eval(expr, &ctx).unwrap_or_else(|message| {
+                errors.push(ExprError {
+                    expr: source.to_string(),
+                    message,
+                });
+                source.to_string()
+            }),
+            Token::Unclosed(source) => {
+                errors.push(ExprError {
+                    expr: source.to_string(),
+                    message: String::from("Missing the closing '}'"),
+                });
+                source.to_string()
+            }
+        })
+        .collect();
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Expr(errors)),
+    }
+}
+
+/// Spreadsheet style name of a cell, e.g. `C5`, from 0-based column and CSV line indices.
+fn cell_name(column: usize, line: usize) -> String {
+    let mut letters = Vec::new();
+    let mut n = column + 1;
+    while n > 0 {
+        letters.push((b'A' + ((n - 1) % 26) as u8) as char);
+        n = (n - 1) / 26;
+    }
+    format!("{}{}", letters.iter().rev().collect::<String>(), line + 1)
+}
+
+/// Print the invalid expressions of a cell, which are only fatal in strict mode.
+fn report_expr_errors(
+    result: Result<(), Error>,
+    csv_path: &Path,
+    column: usize,
+    line: usize,
+    project: &Project,
+) -> Result<(), Error> {
+    let Err(Error::Expr(errors)) = &result else {
+        return result;
+    };
+    let mut reported = project.reported.lock().unwrap();
+    let fresh = errors
+        .iter()
+        .filter(|e| reported.insert((csv_path.to_path_buf(), column, line, e.expr.clone())))
+        .map(|e| {
+            let message = format!("invalid expression `{}`: {}", e.expr, e.message);
+            report_cell_error(csv_path, column, line, &message, &project.options)
+        })
+        .fold(Ok(()), Result::and);
+    // Expressions already reported for another sample still abort the conversion in strict mode.
+    let seen = if project.options.strict {
+        result
+    } else {
+        Ok(())
+    };
+    fresh.and(seen)
+}
+
+mod math_expr {
+    use indexmap::IndexSet;
+    use mexprp::*;
+    use regex::Regex;
+    use std::{
+        borrow::Cow,
+        cell::{Cell, RefCell},
+        rc::Rc,
+        sync::{LazyLock, Mutex},
+    };
+
+    pub(crate) fn format_float(val: Answer<f64>) -> Result<String, MathError> {
+        let v = match val {
+            Answer::Single(v) => v,
+            Answer::Multiple(v) => v[0],
+        };
+        if let Some(s) = as_string(v) {
+            return Ok(s);
+        }
+        let v = operand(v)?;
+        Ok((if v == -0.0 { 0.0 } else { v }).to_string())
+    }
+
+    /// Strings are interned and passed through mexprp as NaN-boxed handles, which survive being
+    /// stored in variables and passed to functions. They are not meant for arithmetic: handles
+    /// are signaling NaNs, which any arithmetic turns into quiet ones, see [`operand`].
+    const STRING_TAG: u64 = 0x7ff4_0000_0000_0000;
+    const STRING_MASK: u64 = 0x0003_ffff_ffff_ffff;
+    const QUIET_BIT: u64 = 0x0008_0000_0000_0000;
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    static STRINGS: LazyLock<Mutex<Strings>> = LazyLock::new(Default::default);
+
+    /// The interned strings, shared by the conversions running at the same time.
+    #[derive(Default)]
+    struct Strings {
+        runs: usize,
+        set: IndexSet<String>,
+    }
+
+    /// Keeps the interned strings alive during a conversion. They are dropped when the last
+    /// running conversion ends, so a long-running host does not accumulate them.
+    pub(crate) struct StringScope;
+
+    impl StringScope {
+        pub(crate) fn enter() -> Self {
+            STRINGS.lock().unwrap().runs += 1;
+            StringScope
+        }
+    }
+
+    impl Drop for StringScope {
+        fn drop(&mut self) {
+            let mut strings = STRINGS.lock().unwrap();
+            strings.runs -= 1;
+            if strings.runs == 0 {
+                strings.set = IndexSet::new();
+            }
+        }
+    }
+
+    pub(crate) fn intern(s: &str) -> f64 {
+        let (id, _) = STRINGS.lock().unwrap().set.insert_full(s.to_string());
+        f64::from_bits(STRING_TAG | id as u64)
+    }
+
+    pub(crate) fn as_string(v: f64) -> Option<String> {
+        let bits = v.to_bits();
+        if bits & !STRING_MASK != STRING_TAG {
+            return None;
+        }
+        let strings = STRINGS.lock().unwrap();
+        strings
+            .set
+            .get_index((bits & STRING_MASK) as usize)
+            .cloned()
+    }
+
+    /// `v` unless it is the result of arithmetic on a string handle, such as `dyn+1` or `-dyn`.
+    pub(crate) fn operand(v: f64) -> Result<f64, MathError> {
+        let bits = v.to_bits();
+        let tag = bits & !STRING_MASK;
+        if tag == STRING_TAG || tag & !(QUIET_BIT | SIGN_BIT) != STRING_TAG {
+            return Ok(v);
+        }
+        let name = as_string(f64::from_bits(STRING_TAG | bits & STRING_MASK)).unwrap_or_default();
+        Err(fail(format!(
+            "the string `{}` can't be used in arithmetic",
+            name
+        )))
+    }
+
+    /// A number, or an interned string if `s` is not one.
+    pub(crate) fn value_of(s: &str) -> f64 {
+        s.trim().parse::<f64>().unwrap_or_else(|_| intern(s.trim()))
+    }
+
+    thread_local! {
+        static DETAIL: RefCell<Option<String>> = const { RefCell::new(None) };
+    }
+
+    /// mexprp errors carry no message of their own, so functions failing for a reason the user
+    /// should see record it here and return [`MathError::Other`].
+    pub(crate) fn fail(message: String) -> MathError {
+        DETAIL.with(|d| *d.borrow_mut() = Some(message));
+        MathError::Other
+    }
+
+    pub(crate) fn describe(e: MathError) -> String {
+        match DETAIL.with(|d| d.borrow_mut().take()) {
+            Some(message) if matches!(e, MathError::Other) => message,
+            _ => e.to_string(),
+        }
+    }
+
+    /// Names of the variables used by `expr`, skipping string literals, numbers and functions.
+    pub(crate) fn variables(expr: &str) -> Vec<&str> {
+        static RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r#"'(?:\\.|[^'\\])*'?|"(?:\\.|[^"\\])*"?|[0-9.]+\w*|([a-zA-Z_]\w*)\s*(\()?"#)
+                .unwrap()
+        });
+        RE.captures_iter(expr)
+            .filter(|caps| caps.get(2).is_none())
+            .filter_map(|caps| Some(caps.get(1)?.as_str()))
+            .collect()
+    }
+
+    /// Replace the quoted string literals of `expr` by variables holding their handles, since
+    /// mexprp has no syntax for strings.
+    fn bind_strings(expr: &str, ctx: &mut Context<f64>) -> Result<String, String> {
+        let mut out = String::new();
+        let mut chars = expr.chars();
+        let mut count = 0;
+        while let Some(c) = chars.next() {
+            if c != '"' && c != '\'' {
+                out.push(c);
+                continue;
+            }
+            let mut literal = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => literal.extend(chars.next()),
+                    Some(q) if q == c => break,
+                    Some(ch) => literal.push(ch),
+                    None => return Err(String::from("Missing the closing quote")),
+                }
+            }
+            let mut name = String::from("__str_");
+            let mut n = count;
+            loop {
+                name.push((b'a' + (n % 26) as u8) as char);
+                n /= 26;
+                if n == 0 {
+                    break;
+                }
+            }
+            count += 1;
+            ctx.set_var(&name, intern(&literal));
+            out.push_str(&name);
+        }
+        Ok(out)
+    }
+
+    pub(crate) fn parse(expr: &str, ctx: &Context<f64>) -> Result<Expression<f64>, String> {
+        let mut ctx = ctx.clone();
+        let expr = bind_strings(&mangle_calls(expr, &ctx), &mut ctx)?;
+        Expression::parse_ctx(&expr, ctx).map_err(|e| e.to_string())
+    }
+
+    pub(crate) fn eval_value(expr: &str, ctx: &Context<f64>) -> Calculation<f64> {
+        parse(expr, ctx).map_err(fail)?.eval()
+    }
+
+    /// Evaluate the content of `${...}` to its output text.
+    pub(crate) fn eval(expr: &str, ctx: &Context<f64>) -> Result<String, String> {
+        eval_value(expr, ctx)
+            .and_then(format_float)
+            .map_err(describe)
+    }
+
+    thread_local! {
+        static DEPTH: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Run `f` one level deeper, or return `None` once `limit` levels are reached.
+    pub(crate) fn nested<T>(limit: usize, f: impl FnOnce() -> T) -> Option<T> {
+        let depth = DEPTH.get();
+        if depth >= limit {
+            return None;
+        }
+        DEPTH.set(depth + 1);
+        let result = f();
+        DEPTH.set(depth);
+        Some(result)
+    }
+
+    /// mexprp names only consist of letters and `_`, so a call like `db2amp(x)` would be read as
+    /// `db*2*amp(x)`. Digits in function names are spelled out to keep them parseable.
+    pub(crate) fn mangle(name: &str) -> String {
+        const DIGITS: [&str; 10] = [
+            "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine",
+        ];
+        name.chars().fold(String::new(), |mut acc, c| {
+            match c.to_digit(10) {
+                Some(d) => {
+                    acc.push_str("__");
+                    acc.push_str(DIGITS[d as usize]);
+                    acc.push_str("__");
+                }
+                None => acc.push(c),
+            }
+            acc
+        })
+    }
+
+    /// Rewrite calls of functions whose name contains digits to their mangled name.
+    pub(crate) fn mangle_calls<'a>(expr: &'a str, ctx: &Context<f64>) -> Cow<'a, str> {
+        static RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"\b([a-zA-Z_][a-zA-Z0-9_]*[0-9][a-zA-Z0-9_]*)\s*\(").unwrap()
+        });
+        RE.replace_all(expr, |caps: &regex::Captures| {
+            let name = &caps[1];
+            let mangled = mangle(name);
+            match ctx.funcs.contains_key(&mangled) {
+                true => format!("{}(", mangled),
+                false => caps[0].to_string(),
+            }
+        })
+    }
+
+    /// FNV-1a, used to derive stable seeds from strings.
+    pub(crate) fn hash_seed(seed: u64, parts: &[&[u8]]) -> u64 {
+        parts
+            .iter()
+            .flat_map(|part| part.iter().chain([0xff].iter()))
+            .fold(0xcbf29ce484222325 ^ seed, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+
+    /// Register `rand()`, `randint(a,b)` and `gauss(mu=0,sigma=1)`, all drawing from one
+    /// splitmix64 sequence started at `seed`.
+    pub(crate) fn set_random(ctx: &mut Context<f64>, seed: u64) {
+        let state = Rc::new(Cell::new(seed));
+        let next = move || {
+            let z = state.get().wrapping_add(0x9e3779b97f4a7c15);
+            state.set(z);
+            let z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            let z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            ((z ^ (z >> 31)) >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let next = Rc::new(next);
+
+        let rand = next.clone();
+        ctx.set_func(
+            "rand",
+            move |args: &[Term<f64>], _ctx: &Context<f64>| -> Calculation<f64> {
+                if !args.is_empty() {
+                    return Err(MathError::IncorrectArguments);
+                }
+                Ok(Answer::Single(rand()))
+            },
+        );
+        let randint = next.clone();
+        ctx.set_func(
+            "randint",
+            move |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                let (lo, hi) = (a.min(b).ceil(), a.max(b).floor());
+                if lo > hi {
+                    return Err(MathError::IncorrectArguments);
+                }
+                finite((lo + (randint() * (hi - lo + 1.0)).floor()).min(hi))
+            },
+        );
+        let gauss = next;
+        ctx.set_func(
+            "gauss",
+            move |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [mu, sigma] = eval_args(args, ctx, &[0.0, 1.0])?;
+                let u1 = 1.0 - gauss();
+                let u2 = gauss();
+                let z = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                finite(mu + sigma * z)
+            },
+        );
+    }
+
+    /// Evaluate exactly `N` arguments, filling the missing trailing ones from `defaults`.
+    pub(crate) fn eval_args<const N: usize>(
+        args: &[Term<f64>],
+        ctx: &Context<f64>,
+        defaults: &[f64],
+    ) -> Result<[f64; N], MathError> {
+        if args.len() > N || args.len() + defaults.len() < N {
+            return Err(MathError::IncorrectArguments);
+        }
+        let mut values = [0.0; N];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = match args.get(i) {
+                Some(arg) => operand(match arg.eval_ctx(ctx)? {
+                    Answer::Single(v) => v,
+                    Answer::Multiple(v) => v[0],
+                })?,
+                None => defaults[defaults.len() - (N - i)],
+            };
+        }
+        Ok(values)
+    }
+
+    fn finite(v: f64) -> Calculation<f64> {
+        match v.is_finite() {
+            true => Ok(Answer::Single(v)),
+            false => Err(MathError::NaN),
+        }
+    }
+
+    pub(crate) fn py_round(n: &f64, decimals: &f64) -> f64 {
+        let factor = 10f64.powi(*decimals as i32);
+        (n * factor).round() / factor
+    }
+
+    type Exp = fn(&[Term<f64>], &Context<f64>) -> Calculation<f64>;
+    pub(crate) const EXPS: &[(&str, Exp)] = &[
         (
             "ceil",
             |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
@@ -393,51 +1703,461 @@ mod math_expr {
                 })
             },
         ),
+        (
+            "int",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [x] = eval_args(args, ctx, &[])?;
+                finite(x.trunc())
+            },
+        ),
+        (
+            "clamp",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [x, lo, hi] = eval_args(args, ctx, &[])?;
+                if lo > hi {
+                    return Err(MathError::IncorrectArguments);
+                }
+                finite(x.clamp(lo, hi))
+            },
+        ),
+        (
+            "lerp",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b, t] = eval_args(args, ctx, &[])?;
+                finite(a + (b - a) * t)
+            },
+        ),
+        (
+            "map",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [x, a, b, c, d] = eval_args(args, ctx, &[])?;
+                if a == b {
+                    return Err(MathError::DivideByZero);
+                }
+                finite(c + (x - a) * (d - c) / (b - a))
+            },
+        ),
+        (
+            "db2amp",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [db] = eval_args(args, ctx, &[])?;
+                finite(10f64.powf(db / 20.0))
+            },
+        ),
+        (
+            "amp2db",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [amp] = eval_args(args, ctx, &[])?;
+                finite(20.0 * amp.log10())
+            },
+        ),
+        (
+            "midi2hz",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [note, a4] = eval_args(args, ctx, &[440.0])?;
+                finite(a4 * ((note - 69.0) / 12.0).exp2())
+            },
+        ),
+        (
+            "hz2midi",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [hz, a4] = eval_args(args, ctx, &[440.0])?;
+                finite(69.0 + 12.0 * (hz / a4).log2())
+            },
+        ),
+        (
+            "cents2ratio",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [cents] = eval_args(args, ctx, &[])?;
+                finite((cents / 1200.0).exp2())
+            },
+        ),
+        (
+            "ratio2cents",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [ratio] = eval_args(args, ctx, &[])?;
+                finite(1200.0 * ratio.log2())
+            },
+        ),
+        (
+            "semi2ratio",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [semi] = eval_args(args, ctx, &[])?;
+                finite((semi / 12.0).exp2())
+            },
+        ),
+        (
+            "ratio2semi",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [ratio] = eval_args(args, ctx, &[])?;
+                finite(12.0 * ratio.log2())
+            },
+        ),
+        (
+            "semi2cents",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [semi] = eval_args(args, ctx, &[])?;
+                finite(semi * 100.0)
+            },
+        ),
+        (
+            "cents2semi",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [cents] = eval_args(args, ctx, &[])?;
+                finite(cents / 100.0)
+            },
+        ),
+        (
+            "velcurve",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [vel, k] = eval_args(args, ctx, &[-2.0])?;
+                let x = (vel / 127.0).clamp(0.0, 1.0);
+                let y = match k == 0.0 {
+                    true => x,
+                    false => ((k * x).exp2() - 1.0) / (k.exp2() - 1.0),
+                };
+                finite(y * 127.0)
+            },
+        ),
+        (
+            "vel2amp",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [vel, k] = eval_args(args, ctx, &[2.0])?;
+                finite((vel / 127.0).clamp(0.0, 1.0).powf(k))
+            },
+        ),
+        (
+            "vel2db",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [vel, k] = eval_args(args, ctx, &[2.0])?;
+                finite(20.0 * k * (vel / 127.0).clamp(0.0, 1.0).log10())
+            },
+        ),
+        (
+            "gt",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(a > b)
+            },
+        ),
+        (
+            "ge",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(a >= b)
+            },
+        ),
+        (
+            "lt",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(a < b)
+            },
+        ),
+        (
+            "le",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(a <= b)
+            },
+        ),
+        (
+            "eq",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(a == b || a.to_bits() == b.to_bits())
+            },
+        ),
+        (
+            "ne",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(!(a == b || a.to_bits() == b.to_bits()))
+            },
+        ),
+        (
+            "between",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [x, lo, hi] = eval_args(args, ctx, &[])?;
+                truth(lo <= x && x <= hi)
+            },
+        ),
+        (
+            "and",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(a != 0.0 && b != 0.0)
+            },
+        ),
+        (
+            "or",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a, b] = eval_args(args, ctx, &[])?;
+                truth(a != 0.0 || b != 0.0)
+            },
+        ),
+        (
+            "not",
+            |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+                let [a] = eval_args(args, ctx, &[])?;
+                truth(a == 0.0)
+            },
+        ),
     ];
+
+    /// Conditions are `1` when true and `0` when false.
+    fn truth(condition: bool) -> Calculation<f64> {
+        Ok(Answer::Single(if condition { 1.0 } else { 0.0 }))
+    }
 }
 
-fn map_to_ctx(row_vars: Option<&HashMap<&str, &str>>) -> Option<mexprp::Context<f64>> {
-    row_vars.map(|row_vars| {
-        let mut ctx = mexprp::Context::<f64>::new();
-        math_expr::EXPS
-            .iter()
-            .for_each(|(name, func)| ctx.set_func(name, func));
-        row_vars.iter().fold(ctx, |mut ctx, (k, v)| {
-            if let Ok(v) = v.parse::<f64>() {
-                ctx.set_var(k, v)
-            }
-            ctx
-        })
+/// Build the expression context of a row. Rows without a sample still get the builtin functions
+/// and project-wide variables, which are shadowed by file name parameters.
+fn map_to_ctx(project: &Project, row_vars: Option<&HashMap<&str, &str>>) -> mexprp::Context<f64> {
+    let mut ctx = mexprp::Context::<f64>::new();
+    math_expr::EXPS
+        .iter()
+        .for_each(|(name, func)| ctx.set_func(&math_expr::mangle(name), func));
+    project.options.functions.iter().for_each(|function| {
+        let (name, func) = (function.name.clone(), function.func.clone());
+        ctx.set_func(
+            &math_expr::mangle(&name),
+            move |args: &[mexprp::Term<f64>], ctx: &mexprp::Context<f64>| {
+                let args = args
+                    .iter()
+                    .map(|arg| {
+                        let v = match arg.eval_ctx(ctx)? {
+                            mexprp::Answer::Single(v) => v,
+                            mexprp::Answer::Multiple(v) => v[0],
+                        };
+                        match math_expr::as_string(v) {
+                            Some(s) => Ok(Value::String(s)),
+                            None => math_expr::operand(v).map(Value::Number),
+                        }
+                    })
+                    .collect::<Result<Vec<_>, mexprp::MathError>>()?;
+                match func(&args) {
+                    Ok(Value::Number(v)) => Ok(mexprp::Answer::Single(v)),
+                    Ok(Value::String(s)) => Ok(mexprp::Answer::Single(math_expr::intern(&s))),
+                    Err(message) => Err(math_expr::fail(format!("`{}`: {}", name, message))),
+                }
+            },
+        );
+    });
+    let globals = Rc::new(project.globals.clone());
+    project.functions.iter().for_each(|function| {
+        let (function, globals) = (function.clone(), globals.clone());
+        ctx.set_func(
+            &math_expr::mangle(&function.name),
+            move |args: &[mexprp::Term<f64>], ctx: &mexprp::Context<f64>| {
+                function.call(args, ctx, &globals)
+            },
+        );
+    });
+    let tables = project.tables.clone();
+    ctx.set_func(
+        "lookup",
+        move |args: &[mexprp::Term<f64>], ctx: &mexprp::Context<f64>| {
+            let [table, key, column] = math_expr::eval_args(args, ctx, &[])?;
+            let name = math_expr::as_string(table)
+                .ok_or_else(|| math_expr::fail(String::from("Expected a table name")))?;
+            let table = tables
+                .get(&name)
+                .ok_or_else(|| math_expr::fail(format!("No table `{}`", name)))?;
+            let value = table
+                .get(key, column)
+                .map_err(|message| math_expr::fail(format!("lookup(\"{}\"): {}", name, message)))?;
+            Ok(mexprp::Answer::Single(value))
+        },
+    );
+    project
+        .globals
+        .iter()
+        .for_each(|(name, value)| ctx.set_var(name, *value));
+    row_vars.into_iter().flatten().fold(ctx, |mut ctx, (k, v)| {
+        ctx.set_var(k, math_expr::value_of(v));
+        ctx
     })
 }
 
-fn merge_row(
-    new_row: &mut [String],
-    old_row: &mut [String],
-    row_vars: Option<&HashMap<&str, &str>>,
-) {
-    let ctx = map_to_ctx(row_vars);
-    new_row
-        .iter_mut()
-        .zip(old_row.iter_mut())
-        .for_each(|(new_cell, old_cell)| {
-            if let Some(_vars) = row_vars {
-                let _ = apply_expr(new_cell, ctx.clone());
-            }
-            if !new_cell.is_empty() {
-                *old_cell = new_cell.clone();
-            }
-        });
+/// Add the random functions to `ctx`, seeded from the project seed, the merge key and the column.
+fn seed_ctx(
+    ctx: &mexprp::Context<f64>,
+    options: &Options,
+    key: &str,
+    column: usize,
+) -> mexprp::Context<f64> {
+    let mut ctx = ctx.clone();
+    let seed = math_expr::hash_seed(
+        options.seed,
+        &[key.as_bytes(), &(column as u64).to_le_bytes()],
+    );
+    math_expr::set_random(&mut ctx, seed);
+    ctx
 }
 
-fn insert_row(mut new_row: Vec<String>, rows_vars: Option<&HashMap<&str, &str>>) -> Vec<String> {
-    if let Some(row_vars) = rows_vars {
-        let ctx = map_to_ctx(Some(row_vars));
-        new_row.iter_mut().for_each(|cell| {
-            let _ = apply_expr(cell, ctx.clone());
-        });
+/// Fill empty `@let` cells with the nearest non-empty cell above them in the merge range.
+fn inherit_lets(rows: &[Vec<String>], let_indices: &IndexMap<String, usize>) -> Vec<Vec<String>> {
+    let mut rows = rows.to_vec();
+    for i in 1..rows.len() {
+        for idx in let_indices.values() {
+            if rows[i][*idx].is_empty() {
+                rows[i][*idx] = rows[i - 1][*idx].clone();
+            }
+        }
+    }
+    rows
+}
+
+/// Give the lower rows of a `@key` merge range the header of its first row, so the keys they
+/// create become regions of their own instead of continuing the previous one.
+fn inherit_header(sample_csv: &SampleCSV, rows: &[Vec<String>]) -> Vec<Vec<String>> {
+    let mut rows = rows.to_vec();
+    let header_idx = sample_csv.anno_indices.get("header").copied();
+    let header = header_idx.and_then(|idx| rows.first()?.get(idx).cloned());
+    if let (Some(idx), Some(header)) = (header_idx, header) {
+        rows.iter_mut()
+            .filter_map(|row| row.get_mut(idx).filter(|cell| cell.is_empty()))
+            .for_each(|cell| *cell = header.clone());
+    }
+    rows
+}
+
+/// Order the `@let` columns of a row so that each variable is computed before the cells using it.
+/// A variable used in its own cell is the file name parameter or project variable it shadows. On a
+/// cyclic dependency, returns the column of one of the variables involved.
+fn let_order(let_indices: &IndexMap<String, usize>, row: &[String]) -> Result<Vec<usize>, usize> {
+    fn visit(
+        n: usize,
+        deps: &[Vec<usize>],
+        states: &mut [u8],
+        order: &mut Vec<usize>,
+    ) -> Result<(), usize> {
+        match states[n] {
+            1 => return Err(n),
+            2 => return Ok(()),
+            _ => states[n] = 1,
+        }
+        deps[n]
+            .iter()
+            .try_for_each(|dep| visit(*dep, deps, states, order))?;
+        states[n] = 2;
+        order.push(n);
+        Ok(())
+    }
+
+    let deps = let_indices
+        .values()
+        .enumerate()
+        .map(|(n, idx)| {
+            tokenize_cell(&row[*idx])
+                .into_iter()
+                .filter_map(|token| match token {
+                    Token::Expr { expr, .. } => Some(expr),
+                    _ => None,
+                })
+                .flat_map(math_expr::variables)
+                .filter_map(|name| let_indices.get_index_of(name))
+                .filter(|dep| *dep != n)
+                .collect::<Vec<usize>>()
+        })
+        .collect::<Vec<_>>();
+    let mut states = vec![0; deps.len()];
+    let mut order = Vec::new();
+    (0..deps.len())
+        .try_for_each(|n| visit(n, &deps, &mut states, &mut order))
+        .map_err(|n| let_indices[n])?;
+    Ok(order.into_iter().map(|n| let_indices[n]).collect())
+}
+
+/// Evaluate the expressions of a row unfolded from line `line` of the CSV file in `ctx`. Rows
+/// without a sample use the CSV path and line as `key`. `@let` cells are evaluated first, and
+/// their values become variables of the other cells.
+fn eval_row(
+    sample_csv: &SampleCSV,
+    row: &mut [String],
+    key: &str,
+    mut ctx: mexprp::Context<f64>,
+    line: usize,
+    project: &Project,
+) -> Result<(), Error> {
+    let options = &project.options;
+    let csv_path = sample_csv.path.as_path();
+    let order = let_order(&sample_csv.let_indices, row).or_else(|idx| {
+        let result = Err(Error::Expr(vec![ExprError {
+            expr: row[idx].clone(),
+            message: String::from("Cyclic @let dependency"),
+        }]));
+        report_expr_errors(result, csv_path, idx, line, project).map(|_| Vec::new())
+    })?;
+    for idx in order {
+        let result = apply_expr(&mut row[idx], seed_ctx(&ctx, options, key, idx));
+        report_expr_errors(result, csv_path, idx, line, project)?;
+        let Some((name, _)) = sample_csv.let_indices.iter().find(|(_, i)| **i == idx) else {
+            continue;
+        };
+        if !row[idx].is_empty() {
+            ctx.set_var(name, math_expr::value_of(&row[idx]));
+        }
     }
+    row.iter_mut().enumerate().try_for_each(|(i, cell)| {
+        if sample_csv.let_indices.values().any(|idx| *idx == i) {
+            return Ok(());
+        }
+        let result = apply_expr(cell, seed_ctx(&ctx, options, key, i));
+        report_expr_errors(result, csv_path, i, line, project)
+    })
+}
+
+fn merge_row(new_row: Vec<String>, old_row: &mut [String]) {
     new_row
+        .into_iter()
+        .zip(old_row.iter_mut())
+        .for_each(|(new_cell, old_cell)| merge_cell(new_cell, old_cell));
+}
+
+/// Cell operators of merging, the rest of a cell starting with `+=` is appended.
+const CLEAR_CELL: &str = "~";
+const KEEP_CELL: &str = "=";
+const APPEND_CELL: &str = "+=";
+
+/// Merge the cell of a later row into the cell of an earlier row. A value overwrites, while an
+/// empty cell or `=` keeps the earlier value, `~` clears it and `+=value` appends to it. An
+/// operator over an earlier operator is combined, so that partial merges can be merged again, and
+/// `finish_row` resolves the operators left.
+fn merge_cell(new_cell: String, old_cell: &mut String) {
+    if new_cell.is_empty() || new_cell == KEEP_CELL {
+        return;
+    }
+    let Some(tail) = new_cell.strip_prefix(APPEND_CELL) else {
+        *old_cell = new_cell;
+        return;
+    };
+    let tail = tail.trim();
+    if old_cell == CLEAR_CELL {
+        *old_cell = tail.to_string();
+    } else if old_cell.is_empty() || old_cell == KEEP_CELL {
+        *old_cell = new_cell;
+    } else if !tail.is_empty() {
+        old_cell.push(' ');
+        old_cell.push_str(tail);
+    }
+}
+
+/// Resolve the cell operators of a merged row against empty values, and unescape the values
+/// written as `\~`, `\=` or `\+=...`.
+fn finish_row(row: &mut [String]) {
+    row.iter_mut().for_each(|cell| {
+        if cell == CLEAR_CELL || cell == KEEP_CELL {
+            cell.clear();
+        } else if let Some(tail) = cell.strip_prefix(APPEND_CELL) {
+            *cell = tail.trim().to_string();
+        } else if let Some(escaped) = cell.strip_prefix('\\') {
+            if escaped == CLEAR_CELL || escaped == KEEP_CELL || escaped.starts_with(APPEND_CELL) {
+                *cell = escaped.to_string();
+            }
+        }
+    });
 }
 
 fn trim_pair(input: &str) -> Option<&str> {
@@ -480,13 +2200,514 @@ fn matching_paths(
         .collect()
 }
 
+/// Pairs the samples matched by the glob of the `@release(vars...)` column with the primary
+/// samples sharing the values of `vars`, generating their `trigger=release` rows.
+#[derive(Debug)]
+struct Release {
+    column: usize,
+    vars: Vec<String>,
+    trigger: usize,
+}
+
+/// A release row of a primary sample, keyed by the release sample path.
+struct ReleaseRow {
+    key: String,
+    primary: String,
+    row: Vec<String>,
+}
+
+impl Release {
+    /// Variables of the release sample are available with this prefix in its row.
+    const PREFIX: &str = "rel_";
+    const SEPARATOR: char = '\u{1e}';
+
+    fn new(sample_csv: &mut SampleCSV, options: &Options) -> Result<Option<Self>, Error> {
+        let Some((column, params)) = sample_csv.anno_params("release") else {
+            return Ok(None);
+        };
+        if params.is_empty() {
+            let message = "@release needs the variables to join on, e.g. `@release(k, v)`";
+            report_anno_error(&sample_csv.path, column, message, options)?;
+            return Ok(None);
+        }
+        let vars = params.into_iter().map(String::from).collect();
+        Ok(Some(Release {
+            column,
+            vars,
+            trigger: sample_csv.opcode_column("trigger"),
+        }))
+    }
+
+    /// Release rows of the primary samples `primaries` of `row`, reporting the primary samples
+    /// without a partner. `expand` matches a glob against the samples, like the sample column.
+    fn pair(
+        &self,
+        row: &[String],
+        primaries: &HashMap<String, Vec<String>>,
+        expand: impl FnOnce(&str, &[String]) -> Result<HashMap<String, Vec<String>>, Error>,
+        rows_vars: &HashMap<&str, HashMap<&str, &str>>,
+        location: (&Path, usize),
+    ) -> Result<Vec<ReleaseRow>, Error> {
+        let pattern = row[self.column].trim();
+        if pattern.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut template = row.to_vec();
+        template[self.column].clear();
+        if template[self.trigger].is_empty() {
+            template[self.trigger] = String::from("release");
+        }
+        let candidates = expand(pattern, &template)?;
+        let join = |path: &str| {
+            let vars = rows_vars.get(path);
+            self.vars
+                .iter()
+                .map(|var| {
+                    vars.and_then(|vars| vars.get(var.as_str()))
+                        .map(|v| math_expr::value_of(v))
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|values| values.into_iter().map(f64::to_bits).collect::<Vec<_>>())
+        };
+        let mut releases = Vec::new();
+        let mut primaries = primaries.keys().collect::<Vec<_>>();
+        primaries.sort_unstable();
+        for primary in primaries {
+            let values = join(primary);
+            let partners = candidates
+                .iter()
+                .filter(|(path, _)| values.is_some() && join(path) == values)
+                .collect::<Vec<_>>();
+            if partners.is_empty() {
+                let vars = rows_vars.get(primary.as_str());
+                let values = self
+                    .vars
+                    .iter()
+                    .map(|var| match vars.and_then(|vars| vars.get(var.as_str())) {
+                        Some(value) => format!("{}={}", var, value),
+                        None => format!("{}=?", var),
+                    })
+                    .collect::<Vec<_>>();
+                eprintln!(
+                    "warning: {}:{}: no release sample of {} with {}",
+                    location.0.display(),
+                    cell_name(self.column, location.1),
+                    primary,
+                    values.join(", ")
+                );
+            }
+            releases.extend(partners.into_iter().map(|(key, row)| ReleaseRow {
+                key: key.clone(),
+                primary: primary.clone(),
+                row: row.clone(),
+            }));
+        }
+        Ok(releases)
+    }
+
+    /// Merge key of the row of the release sample `release` paired with `primary`. It sorts right
+    /// after the primary sample, so each release region follows its sustain region, and a release
+    /// sample shared by several primary samples gets a row for each of them.
+    fn key(primary: &str, release: &str) -> String {
+        format!("{}{}{}", primary, Self::SEPARATOR, release)
+    }
+
+    /// The sample path of a merge key, which is the release sample of a release row.
+    fn path(key: &str) -> &str {
+        key.split_once(Self::SEPARATOR)
+            .map_or(key, |(_, release)| release)
+    }
+
+    /// Add the file name parameters of the release sample `key` to `ctx`.
+    fn bind(&self, ctx: &mut mexprp::Context<f64>, vars: Option<&HashMap<&str, &str>>) {
+        vars.into_iter().flatten().for_each(|(k, v)| {
+            ctx.set_var(&format!("{}{}", Self::PREFIX, k), math_expr::value_of(v));
+        });
+    }
+}
+
+/// Loop variables of the `@foreach(var)` columns. Each non-empty cell holds the values of `var`,
+/// and a row is expanded over the Cartesian product of the values of its cells.
+#[derive(Debug)]
+struct ForEach {
+    columns: Vec<(String, usize)>,
+}
+
+/// Values of the loop variables of an expanded row, in the order of the columns.
+type Bindings = Vec<(String, String)>;
+
+impl ForEach {
+    /// Separates the sample path from the values of the loop variables in the keys of rows.
+    const SEPARATOR: char = '\u{1f}';
+    /// Upper bound of the values of a cell, guarding against a mistyped step.
+    const MAX_VALUES: usize = 65536;
+    /// Upper bound of the rows a row is expanded into, as the product of a few long lists grows
+    /// quickly.
+    const MAX_BINDINGS: usize = 65536;
+
+    fn new(sample_csv: &SampleCSV) -> Option<Self> {
+        let columns = sample_csv
+            .titles
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, title)| {
+                let anno = parse_annotation(title)?;
+                (anno[0] == "foreach" && anno.len() > 1).then(|| (anno[1].to_string(), idx))
+            })
+            .collect::<Vec<_>>();
+        columns.is_empty().not().then_some(ForEach { columns })
+    }
+
+    /// Parse `start..end` or `start..=end` with an optional `, step`, or a list `a, b, ...`.
+    fn values(cell: &str) -> Result<Vec<String>, String> {
+        let items = cell.split(',').map(str::trim).collect::<Vec<_>>();
+        let Some((start, end)) = items[0].split_once("..") else {
+            return Ok(items
+                .into_iter()
+                .map(|item| {
+                    ['"', '\'']
+                        .iter()
+                        .find_map(|q| item.strip_prefix(*q)?.strip_suffix(*q))
+                        .unwrap_or(item)
+                        .to_string()
+                })
+                .collect());
+        };
+        let (end, inclusive) = match end.strip_prefix('=') {
+            Some(end) => (end, true),
+            None => (end, false),
+        };
+        let number = |s: &str| {
+            s.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| format!("`{}` is not a number", s.trim()))
+        };
+        let (start, end) = (number(start)?, number(end)?);
+        let step = match items.as_slice() {
+            [_] => 1.0,
+            [_, step] => number(step)?,
+            _ => return Err(String::from("Expected `start..end, step`")),
+        };
+        if step <= 0.0 {
+            return Err(String::from("The step must be positive"));
+        }
+        let mut values = Vec::new();
+        let sign = if end < start { -1.0 } else { 1.0 };
+        for i in 0.. {
+            let value = math_expr::py_round(&(start + sign * step * i as f64), &9.0);
+            let past = sign * (value - end);
+            if past > 0.0 || (past == 0.0 && !inclusive) {
+                break;
+            }
+            if values.len() == Self::MAX_VALUES {
+                return Err(format!("More than {} values", Self::MAX_VALUES));
+            }
+            values.push(if value == -0.0 { 0.0 } else { value }.to_string());
+        }
+        Ok(values)
+    }
+
+    /// All bindings of `row`, a single empty binding when its cells are empty.
+    fn bindings(
+        &self,
+        row: &[String],
+        (csv_path, line): (&Path, usize),
+        options: &Options,
+    ) -> Result<Vec<Bindings>, Error> {
+        let mut product = vec![Bindings::new()];
+        for (var, idx) in &self.columns {
+            let cell = row[*idx].trim();
+            if cell.is_empty() {
+                continue;
+            }
+            let values = match Self::values(cell) {
+                Ok(values) => values,
+                Err(message) => {
+                    let message = format!("invalid @foreach values `{}`: {}", cell, message);
+                    report_cell_error(csv_path, *idx, line, &message, options)?;
+                    continue;
+                }
+            };
+            if product.len() * values.len() > Self::MAX_BINDINGS {
+                let message = format!(
+                    "@foreach values `{}` expand the row into more than {} rows",
+                    cell,
+                    Self::MAX_BINDINGS
+                );
+                report_cell_error(csv_path, *idx, line, &message, options)?;
+                continue;
+            }
+            product = product
+                .into_iter()
+                .flat_map(|bindings| {
+                    values.iter().map(move |value| {
+                        let mut bindings = bindings.clone();
+                        bindings.push((var.clone(), value.clone()));
+                        bindings
+                    })
+                })
+                .collect();
+        }
+        Ok(product)
+    }
+
+    /// Key of the row of `key` expanded with `bindings`.
+    fn key(key: &str, bindings: &Bindings) -> String {
+        bindings
+            .iter()
+            .fold(key.to_string(), |mut key, (var, value)| {
+                key.push(Self::SEPARATOR);
+                key.push_str(&format!("{}={}", var, value));
+                key
+            })
+    }
+
+    /// The sample path and the bindings of an expanded key.
+    fn split_key(key: &str) -> (&str, Vec<(&str, &str)>) {
+        let mut parts = key.split(Self::SEPARATOR);
+        let path = parts.next().unwrap_or_default();
+        (
+            path,
+            parts.filter_map(|part| part.split_once('=')).collect(),
+        )
+    }
+
+    fn bind(ctx: &mut mexprp::Context<f64>, bindings: &Bindings) {
+        bindings.iter().for_each(|(var, value)| {
+            ctx.set_var(var, math_expr::value_of(value));
+        });
+    }
+}
+
+/// Merge identity of the rows of a merge range chosen by `@key(names...)`, instead of the
+/// sample path. The evaluated cell of the `@key` column is a part of the identity as well.
+#[derive(Debug)]
+struct MergeKey {
+    column: usize,
+    names: Vec<String>,
+}
+
+impl MergeKey {
+    fn new(sample_csv: &SampleCSV) -> Option<Self> {
+        let (column, params) = sample_csv.anno_params("key")?;
+        let names = match params.is_empty() {
+            true => vec![String::from("sample")],
+            false => params.into_iter().map(String::from).collect(),
+        };
+        Some(MergeKey { column, names })
+    }
+
+    /// Identity of `row` of `key`, the sample path expanded by `@foreach`. A name is looked up
+    /// in the opcode and `@let` columns, then in the loop variables and the file name parameters,
+    /// and `sample` is the sample path. The values of the loop variables are always a part of it.
+    fn of(
+        &self,
+        sample_csv: &SampleCSV,
+        row: &[String],
+        key: &str,
+        vars: Option<&HashMap<&str, &str>>,
+    ) -> String {
+        let (path, bindings) = ForEach::split_key(key);
+        let mut parts = self
+            .names
+            .iter()
+            .map(|name| {
+                let column = match name.as_str() {
+                    "sample" => None,
+                    name => sample_csv
+                        .opcode_indices
+                        .get(name)
+                        .or_else(|| sample_csv.let_indices.get(name)),
+                };
+                match (name.as_str(), column) {
+                    (_, Some(idx)) => row[*idx].as_str(),
+                    ("sample", None) => path,
+                    (name, None) => bindings
+                        .iter()
+                        .find(|(var, _)| *var == name)
+                        .map(|(_, value)| *value)
+                        .or_else(|| vars.and_then(|vars| vars.get(name)).copied())
+                        .unwrap_or(""),
+                }
+            })
+            .collect::<Vec<_>>();
+        parts.push(row[self.column].as_str());
+        parts.push(&key[path.len()..]);
+        parts.join("\u{1f}")
+    }
+
+    /// Re-key the evaluated rows of a merge range row, merging the rows with the same identity.
+    fn rekey(
+        &self,
+        sample_csv: &SampleCSV,
+        rows: HashMap<String, Vec<String>>,
+        rows_vars: &HashMap<&str, HashMap<&str, &str>>,
+    ) -> HashMap<String, Vec<String>> {
+        let mut rows = rows.into_iter().collect::<Vec<_>>();
+        rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut keyed: HashMap<String, Vec<String>> = HashMap::new();
+        for (key, row) in rows {
+            let path = Release::path(ForEach::split_key(&key).0);
+            let key = self.of(sample_csv, &row, &key, rows_vars.get(path));
+            match keyed.get_mut(&key) {
+                Some(old_row) => merge_row(row, old_row),
+                None => {
+                    keyed.insert(key, row);
+                }
+            }
+        }
+        keyed
+    }
+}
+
+/// File name parameters of the samples matched in a merge range, read by the aggregate
+/// functions `count()`, `minof()`, `maxof()`, `rank()`, `prev()` and `next()`.
+struct RangeSamples {
+    samples: IndexMap<String, HashMap<String, f64>>,
+}
+
+impl RangeSamples {
+    fn new<'a>(
+        paths: impl Iterator<Item = &'a String>,
+        rows_vars: &HashMap<&str, HashMap<&str, &str>>,
+    ) -> Self {
+        let mut samples = IndexMap::new();
+        paths.for_each(|path| {
+            samples.entry(path.clone()).or_insert_with(|| {
+                rows_vars
+                    .get(path.as_str())
+                    .into_iter()
+                    .flatten()
+                    .map(|(k, v)| (k.to_string(), math_expr::value_of(v)))
+                    .collect()
+            });
+        });
+        RangeSamples { samples }
+    }
+
+    /// Parameters of the samples sharing the value of `by` with the sample `current`.
+    fn group(&self, current: &str, by: Option<&str>) -> Result<Vec<&HashMap<String, f64>>, String> {
+        let key = match by {
+            Some(by) => Some(
+                self.samples
+                    .get(current)
+                    .and_then(|vars| vars.get(by))
+                    .ok_or_else(|| format!("The sample has no parameter `{}`", by))?
+                    .to_bits(),
+            ),
+            None => None,
+        };
+        Ok(self
+            .samples
+            .values()
+            .filter(|vars| match (by, key) {
+                (Some(by), Some(key)) => vars.get(by).is_some_and(|v| v.to_bits() == key),
+                _ => true,
+            })
+            .collect())
+    }
+
+    /// Sorted distinct numeric values of `var` in the group of `current`, and its own value.
+    fn values(
+        &self,
+        current: &str,
+        var: &str,
+        by: Option<&str>,
+    ) -> Result<(Vec<f64>, f64), String> {
+        let value = self
+            .samples
+            .get(current)
+            .and_then(|vars| vars.get(var))
+            .filter(|v| !v.is_nan())
+            .copied()
+            .ok_or_else(|| format!("The sample has no numeric parameter `{}`", var))?;
+        let mut values = self
+            .group(current, by)?
+            .into_iter()
+            .filter_map(|vars| vars.get(var).copied().filter(|v| !v.is_nan()))
+            .collect::<Vec<_>>();
+        values.sort_by(f64::total_cmp);
+        values.dedup();
+        Ok((values, value))
+    }
+
+    /// Add the aggregate functions of the sample `current` to `ctx`. Their arguments are names of
+    /// file name parameters, the optional last one groups the samples by its value.
+    fn register(self: &Arc<Self>, ctx: &mut mexprp::Context<f64>, current: &str) {
+        type Aggregate = fn(&RangeSamples, &str, &[&str]) -> Result<f64, String>;
+        const AGGREGATES: &[(&str, usize, Aggregate)] = &[
+            ("count", 0, |samples, current, names| {
+                Ok(samples.group(current, names.first().copied())?.len() as f64)
+            }),
+            ("minof", 1, |samples, current, names| {
+                let (values, _) = samples.values(current, names[0], names.get(1).copied())?;
+                Ok(values[0])
+            }),
+            ("maxof", 1, |samples, current, names| {
+                let (values, _) = samples.values(current, names[0], names.get(1).copied())?;
+                Ok(values[values.len() - 1])
+            }),
+            ("rank", 1, |samples, current, names| {
+                let (values, value) = samples.values(current, names[0], names.get(1).copied())?;
+                Ok(values.partition_point(|v| *v < value) as f64 + 1.0)
+            }),
+            ("prev", 1, |samples, current, names| {
+                let (values, value) = samples.values(current, names[0], names.get(1).copied())?;
+                let i = values.partition_point(|v| *v < value);
+                Ok(values[i.saturating_sub(1)])
+            }),
+            ("next", 1, |samples, current, names| {
+                let (values, value) = samples.values(current, names[0], names.get(1).copied())?;
+                let i = values.partition_point(|v| *v < value);
+                Ok(values[(i + 1).min(values.len() - 1)])
+            }),
+        ];
+        for (name, arity, aggregate) in AGGREGATES {
+            let (samples, current) = (self.clone(), current.to_string());
+            ctx.set_func(
+                name,
+                move |args: &[mexprp::Term<f64>], _ctx: &mexprp::Context<f64>| {
+                    if args.len() != *arity && args.len() != arity + 1 {
+                        return Err(mexprp::MathError::IncorrectArguments);
+                    }
+                    let names = args
+                        .iter()
+                        .map(|arg| match arg {
+                            mexprp::Term::Var(name) => Ok(name.as_str()),
+                            _ => Err(math_expr::fail(format!(
+                                "`{}` expects names of file name parameters",
+                                name
+                            ))),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    aggregate(&samples, &current, &names)
+                        .map(mexprp::Answer::Single)
+                        .map_err(math_expr::fail)
+                },
+            );
+        }
+    }
+}
+
 fn expand_sheet(
-    rows: &[Vec<String>],
+    sample_csv: &SampleCSV,
+    rows: &[(usize, Vec<String>)],
     sample_paths: &[String],
     rows_vars: &HashMap<&str, HashMap<&str, &str>>,
-    sample_idx: Option<&usize>,
+    (release, merge_key, for_each): (Option<&Release>, Option<&MergeKey>, Option<&ForEach>),
+    project: &Project,
 ) -> Result<Vec<Vec<String>>, Error> {
-    sample_idx
+    let (lines, rows): (Vec<_>, Vec<_>) = rows.iter().cloned().unzip();
+    let rows = &inherit_lets(&rows, &sample_csv.let_indices);
+    let bindings = |row: &[String], line: usize| match for_each {
+        Some(for_each) => for_each.bindings(row, (&sample_csv.path, line), &project.options),
+        None => Ok(vec![Bindings::new()]),
+    };
+    sample_csv
+        .anno_indices
+        .get("sample")
         .and_then(|sample_idx| {
             const PATH_MODIFIER_PASS: fn(&mut String) = |_| {};
             const PATH_MODIFIER_CLEAR: fn(&mut String) = |input| input.clear();
@@ -505,129 +2726,1162 @@ fn expand_sheet(
             })
         })
         .map_or_else(
-            || Ok::<Vec<Vec<String>>, Error>(rows.to_vec()),
+            || {
+                let rows = rows
+                    .par_iter()
+                    .enumerate()
+                    .map(|(i, row)| {
+                        let line = lines[i];
+                        let key = format!("{}:{}", project.relative(&sample_csv.path), line);
+                        bindings(row, line)?
+                            .into_iter()
+                            .map(|bindings| {
+                                let mut row = row.clone();
+                                let mut ctx = map_to_ctx(project, None);
+                                ForEach::bind(&mut ctx, &bindings);
+                                let key = ForEach::key(&key, &bindings);
+                                eval_row(sample_csv, &mut row, &key, ctx, line, project)?;
+                                finish_row(&mut row);
+                                Ok(row)
+                            })
+                            .collect::<Result<Vec<_>, Error>>()
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok::<Vec<Vec<String>>, Error>(rows.into_iter().flatten().collect())
+            },
             |(&sample_idx, path_modifier)| {
-                let r = rows
+                let rows = &match merge_key {
+                    Some(_) => inherit_header(sample_csv, rows),
+                    None => rows.clone(),
+                };
+                let matched = rows
                     .par_iter()
-                    .map(|row| {
-                        let sample_path = row[sample_idx].as_str();
-                        let sample_path = trim_comment_prefix(sample_path)
-                            .or(Some(sample_path))
-                            .and_then(trim_pair)
-                            .unwrap_or(sample_path);
+                    .enumerate()
+                    .map(|(i, row)| {
+                        let sample_path =
+                            eval_glob(sample_csv, row, sample_idx, lines[i], project)?;
+                        let sample_path = sample_path.as_str();
+                        let sample_path = trim_comment_prefix(sample_path).unwrap_or(sample_path);
+                        let sample_path = trim_pair(sample_path).unwrap_or(sample_path);
                         let r = try_get_matcher(sample_path).map(|matcher| {
                             matching_paths(sample_paths, sample_idx, row, matcher, path_modifier)
                         })?;
+                        let releases = match release {
+                            Some(release) => release.pair(
+                                row,
+                                &r,
+                                |pattern, row| {
+                                    try_get_matcher(pattern).map(|matcher| {
+                                        matching_paths(
+                                            sample_paths,
+                                            sample_idx,
+                                            row,
+                                            matcher,
+                                            path_modifier,
+                                        )
+                                    })
+                                },
+                                rows_vars,
+                                (&sample_csv.path, lines[i]),
+                            )?,
+                            None => Vec::new(),
+                        };
+                        Ok((r, releases, bindings(row, lines[i])?))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let samples = Arc::new(RangeSamples::new(
+                    matched.iter().flat_map(|(r, _, _)| r.keys()),
+                    rows_vars,
+                ));
+                let r = matched
+                    .into_par_iter()
+                    .enumerate()
+                    .map(|(i, (r, releases, bindings))| {
+                        let line = lines[i];
+                        let entries =
+                            r.into_iter()
+                                .map(|(key, row)| (key, None, row))
+                                .chain(releases.into_iter().map(|release| {
+                                    (release.key, Some(release.primary), release.row)
+                                }))
+                                .collect::<Vec<_>>();
+                        let mut r: HashMap<String, Vec<String>> = HashMap::new();
+                        for bindings in &bindings {
+                            for (path, primary, row) in &entries {
+                                let sample = primary.as_ref().unwrap_or(path);
+                                let mut ctx = map_to_ctx(project, rows_vars.get(sample.as_str()));
+                                if let (Some(release), Some(_)) = (release, primary) {
+                                    release.bind(&mut ctx, rows_vars.get(path.as_str()));
+                                }
+                                samples.register(&mut ctx, sample);
+                                ForEach::bind(&mut ctx, bindings);
+                                let mut row = row.clone();
+                                let seed = ForEach::key(path, bindings);
+                                eval_row(sample_csv, &mut row, &seed, ctx, line, project)?;
+                                let key = match primary {
+                                    Some(primary) => {
+                                        ForEach::key(&Release::key(primary, path), bindings)
+                                    }
+                                    None => seed,
+                                };
+                                match r.get_mut(&key) {
+                                    Some(old_row) => merge_row(row, old_row),
+                                    None => {
+                                        r.insert(key, row);
+                                    }
+                                }
+                            }
+                        }
+                        if let Some(merge_key) = merge_key {
+                            r = merge_key.rekey(sample_csv, r, rows_vars);
+                        }
                         Ok::<HashMap<String, Vec<String>>, Error>(r)
                     })
-                    .try_reduce(HashMap::new, |mut acc, unfolded_rows| {
-                        unfolded_rows.into_iter().for_each(|(key, mut new_row)| {
-                            acc.entry(key.clone())
-                                .and_modify(|old_row| {
-                                    merge_row(&mut new_row, old_row, rows_vars.get(key.as_str()))
-                                })
-                                .or_insert_with(|| {
-                                    insert_row(new_row, rows_vars.get(key.as_str()))
-                                });
-                        });
-                        Ok(acc)
-                    })?
-                    .into_values()
-                    .collect();
-                Ok(r)
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let location = (sample_csv.path.as_path(), lines.as_slice());
+                Ok(merge_rows(r, sample_idx, location, &project.options))
             },
         )
 }
 
-fn expand_sample_csv(
-    sample_csv: &mut SampleCSV,
-    sample_paths: &[String],
-    rows_vars: &HashMap<&str, HashMap<&str, &str>>,
-) -> Result<(), Error> {
-    let sample_idx = sample_csv.anno_indices.get("sample");
-
-    sample_csv.rows = sample_csv
-        .header_ranges
-        .clone()
-        .into_par_iter()
-        .map(|range| {
-            let rows = &sample_csv.rows[range];
-            expand_sheet(rows, sample_paths, rows_vars, sample_idx)
-        })
-        .try_reduce(Vec::new, |mut acc, partial| {
-            acc.extend(partial);
-            Ok(acc)
-        })?;
+/// Whether `row` is neither commented out nor disabled by its `@enabled` cell, which is `0`,
+/// `false`, `no` or `off` to disable it and may use the project variables in expressions.
+fn is_enabled(
+    sample_csv: &SampleCSV,
+    row: &[String],
+    line: usize,
+    project: &Project,
+) -> Result<bool, Error> {
+    if is_commented(row) {
+        return Ok(false);
+    }
+    let Some(idx) = sample_csv.anno_indices.get("enabled") else {
+        return Ok(true);
+    };
+    let mut cell = row[*idx].trim().to_string();
+    let result = apply_expr(&mut cell, map_to_ctx(project, None));
+    report_expr_errors(result, &sample_csv.path, *idx, line, project)?;
+    Ok(!matches!(
+        cell.trim().to_ascii_lowercase().as_str(),
+        "0" | "false" | "no" | "off"
+    ))
+}
 
-    Ok(())
+/// The `@sample` glob of `row`, whose expressions can use the project variables and the `@let`
+/// variables not depending on file name parameters.
+fn eval_glob(
+    sample_csv: &SampleCSV,
+    row: &[String],
+    sample_idx: usize,
+    line: usize,
+    project: &Project,
+) -> Result<String, Error> {
+    let mut glob = row[sample_idx].clone();
+    if !glob.contains("${") {
+        return Ok(glob);
+    }
+    let mut ctx = map_to_ctx(project, None);
+    for idx in let_order(&sample_csv.let_indices, row).unwrap_or_default() {
+        let mut cell = row[idx].clone();
+        let Some((name, _)) = sample_csv.let_indices.iter().find(|(_, i)| **i == idx) else {
+            continue;
+        };
+        if apply_expr(&mut cell, ctx.clone()).is_ok() && !cell.is_empty() {
+            ctx.set_var(name, math_expr::value_of(&cell));
+        }
+    }
+    let result = apply_expr(&mut glob, ctx);
+    report_expr_errors(result, &sample_csv.path, sample_idx, line, project)?;
+    Ok(glob)
 }
 
-fn generate_sfz_file(path: impl AsRef<Path>, sample_csv: &SampleCSV) -> Result<(), Error> {
-    let mut sfz: String = String::new();
-    for row in sample_csv.rows.iter() {
-        sample_csv
-            .opcode_indices
-            .iter()
-            .filter(|(key, _)| !key.is_empty())
-            .for_each(|(key, idx)| {
-                row.get(*idx).and_then(|value| {
-                    value.is_empty().not().then(|| {
-                        if key.starts_with("__") {
-                            sfz.push_str(&format!("{} ", value));
-                        } else {
-                            sfz.push_str(&format!("{}={} ", key, value));
-                        }
-                    })
-                });
+/// Merge the evaluated rows of a merge range in the order of the CSV rows, so a lower row wins,
+/// and output them by their first appearance. Each of `partials` maps the keys of one CSV row,
+/// at the same index of `lines`, to its rows. With `warn_overrides`, every non-empty value
+/// overridden by a lower row is reported.
+fn merge_rows(
+    partials: Vec<HashMap<String, Vec<String>>>,
+    sample_idx: usize,
+    (csv_path, lines): (&Path, &[usize]),
+    options: &Options,
+) -> Vec<Vec<String>> {
+    let mut merged: IndexMap<String, (Vec<String>, Vec<usize>)> = IndexMap::new();
+    for (i, partial) in partials.into_iter().enumerate() {
+        let line = lines[i];
+        let mut partial = partial.into_iter().collect::<Vec<_>>();
+        partial.sort_unstable_by(|a, b| natural_cmp(&a.0, &b.0));
+        for (key, new_row) in partial {
+            let Some((old_row, lines)) = merged.get_mut(&key) else {
+                let lines = vec![line; new_row.len()];
+                merged.insert(key, (new_row, lines));
+                continue;
+            };
+            new_row.iter().enumerate().for_each(|(idx, new_cell)| {
+                let is_value = |cell: &str| {
+                    !cell.is_empty()
+                        && cell != CLEAR_CELL
+                        && cell != KEEP_CELL
+                        && !cell.starts_with(APPEND_CELL)
+                };
+                let sets = !new_cell.is_empty() && new_cell != KEEP_CELL;
+                let old_cell = &old_row[idx];
+                let overridden = sets
+                    && !new_cell.starts_with(APPEND_CELL)
+                    && new_cell != old_cell
+                    && is_value(old_cell);
+                if overridden && options.warn_overrides {
+                    eprintln!(
+                        "warning: {}:{}: `{}` overrides `{}` of {} for {}",
+                        csv_path.display(),
+                        cell_name(idx, line),
+                        new_cell,
+                        old_cell,
+                        cell_name(idx, lines[idx]),
+                        new_row[sample_idx]
+                    );
+                }
+                if sets {
+                    lines[idx] = line;
+                }
             });
-        sfz.push('\n');
+            merge_row(new_row, old_row);
+        }
     }
-    let mut file = fs::File::create(path)?;
-    file.write_all(sfz.as_bytes())?;
-    Ok(())
+    merged
+        .into_values()
+        .map(|(mut row, _)| {
+            finish_row(&mut row);
+            row
+        })
+        .collect()
 }
 
-#[cfg(test)]
-mod tests {
+/// Report invalid parameters of the annotation of `column`, which abort the conversion in strict
+/// mode.
+fn report_anno_error(
+    csv_path: &Path,
+    column: usize,
+    message: &str,
+    options: &Options,
+) -> Result<(), Error> {
+    report_cell_error(csv_path, column, 0, message, options)
+}
 
-    use super::*;
+/// Report an invalid cell, which aborts the conversion in strict mode.
+fn report_cell_error(
+    csv_path: &Path,
+    column: usize,
+    line: usize,
+    message: &str,
+    options: &Options,
+) -> Result<(), Error> {
+    let source = format!("{}:{}", csv_path.display(), cell_name(column, line));
+    report_error(&source, message, options)
+}
 
-    #[test]
-    fn test_sample_name_paser() {
-        let name = "$0m@_(#)*_kEy60_.str99_pi3.14_zdot0._dotz.0_ddd1.2.3";
-        let result = parse_sample_name(name);
+/// Report a problem at `source`, such as `Piano.csv:B2`, which aborts the conversion in strict
+/// mode.
+fn report_error(source: &str, message: &str, options: &Options) -> Result<(), Error> {
+    let level = if options.strict { "error" } else { "warning" };
+    eprintln!("{}: {}: {}", level, source, message);
+    match options.strict {
+        true => Err(Error::Expr(Vec::new())),
+        false => Ok(()),
+    }
+}
 
-        assert_eq!(result.get("kEy"), Some(&"60"));
-        assert_eq!(result.get(".str"), None);
-        assert_eq!(result.get("str"), None);
-        assert_eq!(result.get("pi"), Some(&"3.14"));
-        assert_eq!(result.get("zdot"), Some(&"0."));
-        assert_eq!(result.get("dotz"), None);
-        assert_eq!(result.get("dotz."), None);
-        assert_eq!(result.get("ddd"), None);
+/// Rows of the merge range `idx` with their lines, without the disabled rows. When the
+/// `@extends` cell of its first row names the `@name` of another range, the rows of that range
+/// come first, with the non-empty cells of the first row overriding those of the first inherited
+/// row. A base range is inherited even if it is disabled itself, so it can serve as a template.
+/// `visiting` holds the ranges being resolved, to report cycles.
+fn range_rows(
+    sample_csv: &SampleCSV,
+    idx: usize,
+    visiting: &mut Vec<usize>,
+    project: &Project,
+) -> Result<Vec<(usize, Vec<String>)>, Error> {
+    let options = &project.options;
+    let range = sample_csv.header_ranges[idx].clone();
+    let is_base = !visiting.is_empty();
+    let mut rows = Vec::new();
+    for i in range.clone() {
+        let row = &sample_csv.rows[i];
+        if !is_enabled(sample_csv, row, i + 1, project)? {
+            // Disabling the first row disables the whole merge range, but not its use as a base.
+            match (i == range.start, is_base) {
+                (true, false) => return Ok(Vec::new()),
+                (false, _) => continue,
+                (true, true) => {}
+            }
+        }
+        let mut row = row.clone();
+        if i == range.start && is_commented(&row) {
+            row[0] = row[0].trim_start().trim_start_matches('#').to_string();
+        }
+        if let Some(idx) = sample_csv.anno_indices.get("enabled") {
+            row[*idx].clear();
+        }
+        rows.push((i + 1, row));
     }
+    let (Some(first), Some(extends)) = (rows.first(), sample_csv.anno_indices.get("extends"))
+    else {
+        return Ok(rows);
+    };
+    let base = first.1[*extends].trim();
+    if base.is_empty() {
+        return Ok(rows);
+    }
+    let name_idx = sample_csv.anno_indices.get("name");
+    let found = sample_csv.header_ranges.iter().position(|range| {
+        name_idx.is_some_and(|n| sample_csv.rows[range.start][*n].trim() == base)
+    });
+    let message = match found {
+        None => format!("no merge range named `{}`", base),
+        Some(found) if found == idx || visiting.contains(&found) => {
+            format!("merge range `{}` extends itself", base)
+        }
+        Some(found) => {
+            visiting.push(idx);
+            let mut inherited = range_rows(sample_csv, found, visiting, project)?;
+            visiting.pop();
+            let (line, first) = rows.remove(0);
+            match inherited.first_mut() {
+                Some((_, row)) => first
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, cell)| !cell.is_empty() && Some(i) != name_idx && i != extends)
+                    .for_each(|(i, cell)| row[i] = cell),
+                None => inherited.push((line, first)),
+            }
+            inherited.extend(rows);
+            return Ok(inherited);
+        }
+    };
+    report_cell_error(
+        &sample_csv.path,
+        *extends,
+        range.start + 1,
+        &message,
+        options,
+    )?;
+    Ok(rows)
+}
 
-    #[test]
-    fn test_create_indices() {
-        let opcodes =
-            csv::StringRecord::from(vec!["@raw".to_string(), "@sample(path)".to_string()]);
-        let mut opcode_indices = IndexMap::new();
-        let mut anno_indices = HashMap::new();
-        create_indices(opcodes, &mut opcode_indices, &mut anno_indices);
+/// How `@keyspread(policy)` shares the keys between two neighboring root keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SplitPolicy {
+    /// Each half goes to the nearer root key.
+    Midpoint,
+    /// Samples are only transposed up.
+    Up,
+    /// Samples are only transposed down.
+    Down,
+}
 
-        assert_eq!(opcode_indices.len(), 2);
-        assert_eq!(anno_indices.len(), 2);
-        assert_eq!(opcode_indices.get("_raw_0"), Some(&0));
-        assert_eq!(opcode_indices.get("path"), Some(&1));
-        assert_eq!(anno_indices.get("raw"), Some(&0));
-        assert_eq!(anno_indices.get("sample"), Some(&1));
+/// Fills `lokey`/`hikey` of the rows of a merge range from the root keys of the `@keyspread`
+/// column, so that the regions tile the keyboard.
+#[derive(Debug)]
+struct KeySpread {
+    column: usize,
+    sample: Option<usize>,
+    policy: SplitPolicy,
+    lokey: usize,
+    hikey: usize,
+}
+
+impl KeySpread {
+    fn new(sample_csv: &mut SampleCSV, options: &Options) -> Result<Option<Self>, Error> {
+        let Some((column, params)) = sample_csv.anno_params("keyspread") else {
+            return Ok(None);
+        };
+        let policy = match params.first().copied().unwrap_or("midpoint") {
+            "midpoint" => SplitPolicy::Midpoint,
+            "up" => SplitPolicy::Up,
+            "down" => SplitPolicy::Down,
+            policy => {
+                let message = format!(
+                    "unknown @keyspread policy `{}`, expected `midpoint`, `up` or `down`",
+                    policy
+                );
+                report_anno_error(&sample_csv.path, column, &message, options)?;
+                SplitPolicy::Midpoint
+            }
+        };
+        Ok(Some(KeySpread {
+            column,
+            sample: sample_csv.anno_indices.get("sample").copied(),
+            policy,
+            lokey: sample_csv.opcode_column("lokey"),
+            hikey: sample_csv.opcode_column("hikey"),
+        }))
     }
 
-    #[test]
-    fn test_creat_header_ranges() {
-        let rows: Vec<Vec<String>> = vec![
-            vec!["".to_string(), "b".to_string()],
-            vec!["".to_string(), "d".to_string()],
+    /// Rows with an empty `@keyspread` cell are skipped, and non-empty `lokey`/`hikey` cells are
+    /// kept.
+    fn apply(
+        &self,
+        rows: &mut [Vec<String>],
+        (csv_path, line): (&Path, usize),
+        project: &Project,
+    ) -> Result<(), Error> {
+        let mut roots = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let cell = row[self.column].trim();
+            if cell.is_empty() {
+                roots.push(None);
+                continue;
+            }
+            match cell.parse::<f64>() {
+                Ok(key) if (0.0..=127.0).contains(&key.round()) => {
+                    roots.push(Some(key.round() as i64))
+                }
+                _ => {
+                    let result = Err(Error::Expr(vec![ExprError {
+                        expr: cell.to_string(),
+                        message: format!(
+                            "@keyspread expects a MIDI key in [0,127]{}",
+                            sample_of(row, self.sample)
+                        ),
+                    }]));
+                    report_expr_errors(result, csv_path, self.column, line, project)?;
+                    roots.push(None);
+                }
+            }
+        }
+        let mut keys = roots.iter().flatten().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+
+        for (row, root) in rows.iter_mut().zip(roots) {
+            let Some(root) = root else {
+                continue;
+            };
+            let i = keys.partition_point(|k| *k < root);
+            let (prev, next) = (i.checked_sub(1).map(|i| keys[i]), keys.get(i + 1).copied());
+            let (lo, hi) = match self.policy {
+                SplitPolicy::Midpoint => (
+                    prev.map_or(0, |prev| (prev + root) / 2 + 1),
+                    next.map_or(127, |next| (root + next) / 2),
+                ),
+                SplitPolicy::Up => (root, next.map_or(127, |next| next - 1)),
+                SplitPolicy::Down => (prev.map_or(0, |prev| prev + 1), root),
+            };
+            for (idx, value) in [(self.lokey, lo), (self.hikey, hi)] {
+                if row[idx].is_empty() {
+                    row[idx] = value.to_string();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How `@velspread` places the boundaries between velocity layers.
+#[derive(Debug, Clone, PartialEq)]
+enum VelSplit {
+    /// Layers of equal width.
+    Even,
+    /// Boundaries shaped by `nl(x,k)`.
+    Nl(f64),
+    /// Explicit `hivel` of every layer but the last one.
+    Bounds(Vec<i64>),
+}
+
+/// Fills `lovel`/`hivel` and optionally the `xfin_*`/`xfout_*` crossfade opcodes of the rows of
+/// a merge range from the layers of the `@velspread` column. Rows sharing the same root key are
+/// split together.
+#[derive(Debug)]
+struct VelSpread {
+    column: usize,
+    sample: Option<usize>,
+    split: VelSplit,
+    /// Width of the crossfades in velocity steps, `0` for none.
+    xfade: i64,
+    /// Column of the root key: `@keyspread`, `pitch_keycenter` or `key`.
+    group: Option<usize>,
+    lovel: usize,
+    hivel: usize,
+    /// `xfin_lovel`, `xfin_hivel`, `xfout_lovel` and `xfout_hivel`.
+    xf: Option<[usize; 4]>,
+}
+
+impl VelSpread {
+    fn new(sample_csv: &mut SampleCSV, options: &Options) -> Result<Option<Self>, Error> {
+        let Some((column, params)) = sample_csv.anno_params("velspread") else {
+            return Ok(None);
+        };
+        let mut xfade = 0;
+        let mut args = Vec::new();
+        for param in params {
+            match param.strip_prefix("xf=").map(|w| w.trim().parse::<i64>()) {
+                Some(Ok(w)) if (0..=127).contains(&w) => xfade = w,
+                Some(_) => {
+                    let message = format!("invalid @velspread crossfade `{}`", param);
+                    report_anno_error(&sample_csv.path, column, &message, options)?;
+                }
+                None => args.push(param),
+            }
+        }
+        let bounds = args
+            .iter()
+            .map(|a| a.parse::<i64>().ok().filter(|b| (1..127).contains(b)))
+            .collect::<Option<Vec<_>>>();
+        let split = match (args.as_slice(), bounds) {
+            ([] | ["even"], _) => VelSplit::Even,
+            (["nl"], _) => VelSplit::Nl(-2.0),
+            (["nl", k], _) if k.parse::<f64>().is_ok() => VelSplit::Nl(k.parse().unwrap()),
+            (_, Some(bounds)) if is_ascending(&bounds) => VelSplit::Bounds(bounds),
+            _ => {
+                let message = format!(
+                    "unknown @velspread mode `{}`, expected `even`, `nl`, `nl, k` or strictly \
+                     ascending boundaries in [1,126]",
+                    args.join(", ")
+                );
+                report_anno_error(&sample_csv.path, column, &message, options)?;
+                VelSplit::Even
+            }
+        };
+        let group = sample_csv
+            .anno_indices
+            .get("keyspread")
+            .or_else(|| sample_csv.opcode_indices.get("pitch_keycenter"))
+            .or_else(|| sample_csv.opcode_indices.get("key"))
+            .copied();
+        let lovel = sample_csv.opcode_column("lovel");
+        let hivel = sample_csv.opcode_column("hivel");
+        let xf = (xfade > 0).then(|| {
+            ["xfin_lovel", "xfin_hivel", "xfout_lovel", "xfout_hivel"]
+                .map(|name| sample_csv.opcode_column(name))
+        });
+        Ok(Some(VelSpread {
+            column,
+            sample: sample_csv.anno_indices.get("sample").copied(),
+            split,
+            xfade,
+            group,
+            lovel,
+            hivel,
+            xf,
+        }))
+    }
+
+    /// `hivel` of each of `n` layers without crossfades. Fails if explicit boundaries do not match
+    /// `n`, or if the layers are too many to get distinct velocities.
+    fn bounds(&self, n: usize) -> Result<Vec<i64>, &'static str> {
+        let mut bounds = match &self.split {
+            VelSplit::Even => (1..n)
+                .map(|i| (i as f64 * 127.0 / n as f64).round() as i64)
+                .collect(),
+            VelSplit::Nl(k) => (1..n)
+                .map(|i| {
+                    let x = i as f64 / n as f64;
+                    let y = match *k == 0.0 {
+                        true => x,
+                        false => ((k * x).exp2() - 1.0) / (k.exp2() - 1.0),
+                    };
+                    (y * 127.0).round().clamp(1.0, 126.0) as i64
+                })
+                .collect(),
+            VelSplit::Bounds(bounds) if bounds.len() + 1 == n => bounds.clone(),
+            VelSplit::Bounds(_) => return Err("do not match the boundaries of @velspread"),
+        };
+        bounds.push(127);
+        match is_ascending(&bounds) {
+            true => Ok(bounds),
+            false => Err("can't be split into distinct velocity ranges by @velspread"),
+        }
+    }
+
+    /// Rows with an empty `@velspread` cell are skipped, and non-empty cells are kept.
+    fn apply(
+        &self,
+        rows: &mut [Vec<String>],
+        (csv_path, line): (&Path, usize),
+        project: &Project,
+    ) -> Result<(), Error> {
+        let mut layers = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let cell = row[self.column].trim();
+            if cell.is_empty() {
+                layers.push(None);
+                continue;
+            }
+            match cell.parse::<f64>() {
+                Ok(layer) if layer.is_finite() => {
+                    let group = self.group.map_or("", |idx| row[idx].trim());
+                    layers.push(Some((group.to_string(), layer)))
+                }
+                _ => {
+                    let result = Err(Error::Expr(vec![ExprError {
+                        expr: cell.to_string(),
+                        message: format!(
+                            "@velspread expects a number{}",
+                            sample_of(row, self.sample)
+                        ),
+                    }]));
+                    report_expr_errors(result, csv_path, self.column, line, project)?;
+                    layers.push(None);
+                }
+            }
+        }
+        let mut groups = HashMap::<&str, Vec<f64>>::new();
+        layers.iter().flatten().for_each(|(group, layer)| {
+            groups.entry(group).or_default().push(*layer);
+        });
+        let mut splits = HashMap::new();
+        for (group, values) in groups.iter_mut() {
+            values.sort_by(f64::total_cmp);
+            values.dedup();
+            match self.bounds(values.len()) {
+                Ok(bounds) => {
+                    splits.insert(*group, bounds);
+                }
+                Err(reason) => {
+                    let message = format!(
+                        "{} layers of the root key `{}` {}",
+                        values.len(),
+                        group,
+                        reason
+                    );
+                    report_anno_error(csv_path, self.column, &message, &project.options)?;
+                }
+            }
+        }
+
+        for (row, layer) in rows.iter_mut().zip(&layers) {
+            let Some((group, layer)) = layer else {
+                continue;
+            };
+            let (Some(values), Some(bounds)) =
+                (groups.get(group.as_str()), splits.get(group.as_str()))
+            else {
+                continue;
+            };
+            let i = values.partition_point(|v| v < layer);
+            // The crossfade around the boundary `b` spans `xfade` velocities from `b - xfade/2 + 1`.
+            let xf_lo = |b: i64| (b - self.xfade / 2 + 1).clamp(1, 127);
+            let xf_hi = |b: i64| (b - self.xfade / 2 + self.xfade).clamp(1, 127);
+            let below = i.checked_sub(1).map(|i| bounds[i]);
+            let above = (i + 1 < bounds.len()).then(|| bounds[i]);
+            let mut values = vec![
+                (self.lovel, Some(below.map_or(1, xf_lo))),
+                (self.hivel, Some(above.map_or(127, xf_hi))),
+            ];
+            if let Some([in_lo, in_hi, out_lo, out_hi]) = self.xf {
+                values.extend([
+                    (in_lo, below.map(xf_lo)),
+                    (in_hi, below.map(xf_hi)),
+                    (out_lo, above.map(xf_lo)),
+                    (out_hi, above.map(xf_hi)),
+                ]);
+            }
+            for (idx, value) in values {
+                if let Some(value) = value.filter(|_| row[idx].is_empty()) {
+                    row[idx] = value.to_string();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether each velocity boundary is above the previous one, so no layer is empty.
+fn is_ascending(bounds: &[i64]) -> bool {
+    bounds.windows(2).all(|w| w[0] < w[1])
+}
+
+/// ` for <sample>` naming the sample of an expanded row in messages, as its cells may come from
+/// any row of the merge range.
+fn sample_of(row: &[String], sample: Option<usize>) -> String {
+    sample
+        .and_then(|idx| row.get(idx))
+        .filter(|path| !path.is_empty())
+        .map(|path| format!(" for {}", path))
+        .unwrap_or_default()
+}
+
+/// Assigns `seq_length`/`seq_position`, or `lorand`/`hirand` in `random` mode, to the rows of a
+/// merge range sharing the same value of the `@rr` column and the same `trigger`, so release
+/// regions are cycled apart from the sustain regions.
+#[derive(Debug)]
+struct RoundRobin {
+    column: usize,
+    random: bool,
+    sample: Option<usize>,
+    trigger: Option<usize>,
+    /// `seq_length` and `seq_position`, or `lorand` and `hirand`.
+    opcodes: [usize; 2],
+}
+
+impl RoundRobin {
+    fn new(sample_csv: &mut SampleCSV, options: &Options) -> Result<Option<Self>, Error> {
+        let Some((column, params)) = sample_csv.anno_params("rr") else {
+            return Ok(None);
+        };
+        let random = match params.first().copied().unwrap_or("seq") {
+            "seq" => false,
+            "random" => true,
+            mode => {
+                let message = format!("unknown @rr mode `{}`, expected `seq` or `random`", mode);
+                report_anno_error(&sample_csv.path, column, &message, options)?;
+                false
+            }
+        };
+        let names = match random {
+            true => ["lorand", "hirand"],
+            false => ["seq_length", "seq_position"],
+        };
+        Ok(Some(RoundRobin {
+            column,
+            random,
+            sample: sample_csv.anno_indices.get("sample").copied(),
+            trigger: sample_csv.opcode_indices.get("trigger").copied(),
+            opcodes: names.map(|name| sample_csv.opcode_column(name)),
+        }))
+    }
+
+    /// Positions follow the natural order of the sample paths, so `rr2` comes before `rr10`. Rows
+    /// with an empty `@rr` cell are skipped, and non-empty cells are kept.
+    fn apply(&self, rows: &mut [Vec<String>]) {
+        let mut groups = IndexMap::<(&str, &str), Vec<usize>>::new();
+        rows.iter().enumerate().for_each(|(i, row)| {
+            let cell = row[self.column].trim();
+            let trigger = self.trigger.map_or("", |idx| row[idx].trim());
+            if !cell.is_empty() {
+                groups.entry((trigger, cell)).or_default().push(i);
+            }
+        });
+        let mut positions = Vec::new();
+        for members in groups.into_values() {
+            let mut members = members;
+            if let Some(sample) = self.sample {
+                members.sort_by(|a, b| natural_cmp(&rows[*a][sample], &rows[*b][sample]));
+            }
+            let n = members.len();
+            positions.extend(members.into_iter().enumerate().map(|(i, row)| (row, i, n)));
+        }
+        for (row, i, n) in positions {
+            let values = match self.random {
+                true => [i, i + 1]
+                    .map(|i| math_expr::py_round(&(i as f64 / n as f64), &4.0).to_string()),
+                false => [n.to_string(), (i + 1).to_string()],
+            };
+            for (idx, value) in self.opcodes.into_iter().zip(values) {
+                if rows[row][idx].is_empty() {
+                    rows[row][idx] = value;
+                }
+            }
+        }
+    }
+}
+
+/// Compare strings with runs of digits compared by their numeric value.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    static RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+|\D+").unwrap());
+    let mut a_runs = RE.find_iter(a).map(|m| m.as_str());
+    let mut b_runs = RE.find_iter(b).map(|m| m.as_str());
+    loop {
+        let ordering = match (a_runs.next(), b_runs.next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(x), Some(y)) => match (x.parse::<u128>(), y.parse::<u128>()) {
+                (Ok(x), Ok(y)) => x.cmp(&y),
+                _ => x.cmp(y),
+            },
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+}
+
+fn expand_sample_csv(
+    sample_csv: &mut SampleCSV,
+    sample_paths: &[String],
+    rows_vars: &HashMap<&str, HashMap<&str, &str>>,
+    project: &Project,
+) -> Result<(), Error> {
+    sample_csv
+        .let_indices
+        .iter()
+        .filter(|(name, _)| project.globals.contains_key(name.as_str()))
+        .for_each(|(name, idx)| {
+            eprintln!(
+                "warning: {}:{}: @let({}) shadows the project variable `{}`",
+                sample_csv.path.display(),
+                cell_name(*idx, 0),
+                name,
+                name
+            )
+        });
+    let key_spread = KeySpread::new(sample_csv, &project.options)?;
+    let vel_spread = VelSpread::new(sample_csv, &project.options)?;
+    let release = Release::new(sample_csv, &project.options)?;
+    let round_robin = RoundRobin::new(sample_csv, &project.options)?;
+    let merge_key = MergeKey::new(sample_csv);
+    let for_each = ForEach::new(sample_csv);
+    let group_opcodes = project.options.group_opcodes && !has_header(sample_csv, "<group>");
+    let ranges = sample_csv
+        .header_ranges
+        .iter()
+        .enumerate()
+        .map(|(i, range)| {
+            let rows = range_rows(sample_csv, i, &mut Vec::new(), project)?;
+            Ok((range.start + 1, rows))
+        })
+        .collect::<Result<Vec<_>, Error>>()?
+        .into_par_iter()
+        .map(|(line, range)| {
+            let post = (release.as_ref(), merge_key.as_ref(), for_each.as_ref());
+            let mut rows =
+                expand_sheet(sample_csv, &range, sample_paths, rows_vars, post, project)?;
+            if let Some(idx) = sample_csv.anno_indices.get("where") {
+                rows.retain(|row| row[*idx].trim().parse::<f64>() != Ok(0.0));
+            }
+            if let Some(key_spread) = &key_spread {
+                key_spread.apply(&mut rows, (&sample_csv.path, line), project)?;
+            }
+            if let Some(vel_spread) = &vel_spread {
+                vel_spread.apply(&mut rows, (&sample_csv.path, line), project)?;
+            }
+            if let Some(round_robin) = &round_robin {
+                round_robin.apply(&mut rows);
+            }
+            Ok::<(usize, Vec<Vec<String>>), Error>((line, rows))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut grouped = false;
+    sample_csv.rows = Vec::new();
+    for (line, mut rows) in ranges {
+        if group_opcodes {
+            hoist_opcodes(sample_csv, &mut rows, &mut grouped);
+        }
+        if project.options.comments && !rows.is_empty() {
+            rows.insert(0, comment_row(sample_csv, line));
+        }
+        sample_csv.rows.extend(rows);
+    }
+    if project.options.default_path {
+        factor_default_path(sample_csv);
+    }
+    if project.options.comments {
+        sample_csv.rows.insert(0, comment_row(sample_csv, 0));
+    }
+
+    Ok(())
+}
+
+/// Whether a row of the sheet has the header `name`, such as `<group>`.
+fn has_header(sample_csv: &SampleCSV, name: &str) -> bool {
+    let Some(header_idx) = sample_csv.anno_indices.get("header") else {
+        return false;
+    };
+    sample_csv.rows.iter().any(|row| {
+        row.get(*header_idx)
+            .is_some_and(|header| header.trim().eq_ignore_ascii_case(name))
+    })
+}
+
+/// Move the opcodes with the same value in every `<region>` row of a merge range into a `<group>`
+/// row before them. `@raw` columns are kept on the regions, as their text may not be an opcode.
+/// `grouped` tells whether such a `<group>` is still in effect, in which case the regions of a
+/// range sharing nothing get an empty `<group>` so they don't inherit its opcodes.
+fn hoist_opcodes(sample_csv: &SampleCSV, rows: &mut Vec<Vec<String>>, grouped: &mut bool) {
+    let Some(&header_idx) = sample_csv.anno_indices.get("header") else {
+        return;
+    };
+    let is_region = |row: &Vec<String>| {
+        row.get(header_idx)
+            .is_some_and(|header| header.trim().eq_ignore_ascii_case("<region>"))
+    };
+    let mut group = vec![String::new(); header_idx + 1];
+    group[header_idx] = String::from("<group>");
+    if rows.len() < 2 || !rows.iter().all(is_region) {
+        if let Some(first) = rows.iter().position(is_region).filter(|_| *grouped) {
+            rows.insert(first, group);
+            *grouped = false;
+        }
+        return;
+    }
+    let shared = sample_csv
+        .opcode_indices
+        .iter()
+        .filter(|(key, _)| !key.is_empty() && !key.starts_with("__"))
+        .map(|(_, idx)| *idx)
+        .filter(|idx| {
+            let value = rows[0].get(*idx).filter(|value| !value.is_empty());
+            value.is_some_and(|value| rows.iter().all(|row| row.get(*idx) == Some(value)))
+        })
+        .collect::<Vec<_>>();
+    if shared.is_empty() {
+        if *grouped {
+            rows.insert(0, group);
+            *grouped = false;
+        }
+        return;
+    }
+    group.resize(rows[0].len(), String::new());
+    for idx in shared {
+        group[idx] = rows[0][idx].clone();
+        rows.iter_mut().for_each(|row| row[idx].clear());
+    }
+    rows.insert(0, group);
+    *grouped = true;
+}
+
+/// Write the common directory of the `sample` paths into the `<control>` rows of the sheet and strip
+/// it from the paths, keeping their quotes. As every `<control>` header resets `default_path`, it
+/// goes into each of them, and into a new one at the top unless a `<control>` row precedes the
+/// first sample. Cleared paths and built-in `*` samples are left out, and so are sheets setting
+/// `default_path` themselves.
+fn factor_default_path(sample_csv: &mut SampleCSV) {
+    let (Some(&header_idx), Some(&sample_idx)) = (
+        sample_csv.anno_indices.get("header"),
+        sample_csv.anno_indices.get("sample"),
+    ) else {
+        return;
+    };
+    if sample_csv.opcode_indices.get("sample") != Some(&sample_idx)
+        || sample_csv.opcode_indices.contains_key("default_path")
+    {
+        return;
+    }
+    let paths = sample_csv
+        .rows
+        .iter()
+        .enumerate()
+        .filter_map(|(i, row)| {
+            let cell = row.get(sample_idx)?;
+            let path = trim_pair(cell).unwrap_or(cell);
+            let quoted = path.len() != cell.len();
+            (!path.is_empty() && !path.starts_with('*')).then(|| (i, path.to_string(), quoted))
+        })
+        .collect::<Vec<_>>();
+    let Some(common) = paths
+        .iter()
+        .map(|(_, path, _)| path.as_str())
+        .reduce(|common, path| {
+            let len = common
+                .bytes()
+                .zip(path.bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            let dir = common.as_bytes()[..len]
+                .iter()
+                .rposition(|b| matches!(b, b'/' | b'\\'));
+            &common[..dir.map_or(0, |idx| idx + 1)]
+        })
+    else {
+        return;
+    };
+    let Some(prefix) = common
+        .rfind(['/', '\\'])
+        .map(|idx| common[..=idx].to_string())
+    else {
+        return;
+    };
+    if prefix.trim_start_matches('.').len() <= 1 {
+        return;
+    }
+    let quoted = paths.iter().any(|(_, _, quoted)| *quoted);
+    let first_sample = paths[0].0;
+    for (i, path, quoted) in paths {
+        let path = &path[prefix.len()..];
+        sample_csv.rows[i][sample_idx] = match quoted {
+            true => format!("\"{}\"", path),
+            false => path.to_string(),
+        };
+    }
+    let default_path = match quoted {
+        true => format!("\"{}\"", prefix),
+        false => prefix,
+    };
+    let idx = sample_csv.opcode_column("default_path");
+    let is_control = |row: &Vec<String>| {
+        row.get(header_idx)
+            .is_some_and(|header| header.trim().eq_ignore_ascii_case("<control>"))
+    };
+    let controls = (0..sample_csv.rows.len())
+        .filter(|i| is_control(&sample_csv.rows[*i]))
+        .collect::<Vec<_>>();
+    for i in &controls {
+        let row = &mut sample_csv.rows[*i];
+        row.resize(row.len().max(idx + 1), String::new());
+        row[idx] = default_path.clone();
+    }
+    if controls.first().is_none_or(|i| *i > first_sample) {
+        let mut control = vec![String::new(); idx + 1];
+        control[header_idx] = String::from("<control>");
+        control[idx] = default_path;
+        sample_csv.rows.insert(0, control);
+    }
+}
+
+/// A row only writing a comment in the `@header` column, the banner for `line` 0 and otherwise the
+/// source of the merge range starting at `line`.
+fn comment_row(sample_csv: &SampleCSV, line: usize) -> Vec<String> {
+    let name = sample_csv
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let comment = match line {
+        0 => format!(
+            "// Generated by csv2sfz {} from {}",
+            env!("CARGO_PKG_VERSION"),
+            name
+        ),
+        line => format!("// {}:{}", name, line + 1),
+    };
+    let header_idx = sample_csv.anno_indices.get("header").copied().unwrap_or(0);
+    let mut row = vec![String::new(); header_idx + 1];
+    row[header_idx] = comment;
+    row
+}
+
+fn generate_sfz_file(path: impl AsRef<Path>, sample_csv: &SampleCSV) -> Result<(), Error> {
+    let sfz = render_sfz(sample_csv);
+    let mut file = fs::File::create(path)?;
+    file.write_all(sfz.as_bytes())?;
+    Ok(())
+}
+
+/// SFZ text of the expanded rows. The cell of the `@comment` column is written as a trailing
+/// comment, or on the line before with `@comment(before)`.
+fn render_sfz(sample_csv: &SampleCSV) -> String {
+    let mut sfz: String = String::new();
+    let comment = sample_csv.anno_params("comment").map(|(idx, params)| {
+        let before = params.first() == Some(&"before");
+        (idx, before)
+    });
+    for row in sample_csv.rows.iter() {
+        // Each line of a multi-line comment is commented out, or it would be read as opcodes.
+        let comment = comment.and_then(|(idx, before)| {
+            let text = row.get(idx)?.trim();
+            let text = text
+                .lines()
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join("\n// ");
+            text.is_empty().not().then_some((text, before))
+        });
+        if let Some((text, true)) = &comment {
+            sfz.push_str(&format!("// {}\n", text));
+        }
+        let mut line = String::new();
+        sample_csv
+            .opcode_indices
+            .iter()
+            .filter(|(key, _)| !key.is_empty())
+            .for_each(|(key, idx)| {
+                row.get(*idx).and_then(|value| {
+                    value.is_empty().not().then(|| {
+                        if key.starts_with("__") {
+                            line.push_str(&format!("{} ", value));
+                        } else {
+                            line.push_str(&format!("{}={} ", key, value));
+                        }
+                    })
+                });
+            });
+        // Rows of a comment, such as the source of a merge range, are not padded like regions.
+        if line.starts_with("//") {
+            line.truncate(line.trim_end().len());
+        }
+        sfz.push_str(&line);
+        if let Some((text, false)) = &comment {
+            sfz.push_str(&format!("// {}", text));
+        }
+        sfz.push('\n');
+    }
+    sfz
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    macro_rules! vec_str {
+        ($($s:expr),*) => (vec![$($s.to_string()),*]);
+    }
+
+    /// A `test.csv` sheet whose indices are derived from `titles` as [`parse_sample_csv`] does.
+    fn sample_csv(
+        titles: &[&str],
+        rows: Vec<Vec<String>>,
+        header_ranges: Vec<Range<usize>>,
+    ) -> SampleCSV {
+        let mut opcode_indices = IndexMap::new();
+        let mut anno_indices = HashMap::new();
+        let mut let_indices = IndexMap::new();
+        create_indices(
+            csv::StringRecord::from(titles),
+            &mut opcode_indices,
+            &mut anno_indices,
+            &mut let_indices,
+        );
+        SampleCSV {
+            path: PathBuf::from("test.csv"),
+            opcode_indices,
+            anno_indices,
+            let_indices,
+            titles: titles.iter().map(|s| s.to_string()).collect(),
+            header_ranges,
+            rows,
+        }
+    }
+
+    #[test]
+    fn test_sample_name_paser() {
+        let name = "$0m@_(#)*_kEy60_.str99_pi3.14_zdot0._dotz.0_ddd1.2.3_dyn=mf";
+        let result = parse_sample_name(name);
+
+        assert_eq!(result.get("kEy"), Some(&"60"));
+        assert_eq!(result.get(".str"), None);
+        assert_eq!(result.get("str"), None);
+        assert_eq!(result.get("pi"), Some(&"3.14"));
+        assert_eq!(result.get("zdot"), Some(&"0."));
+        assert_eq!(result.get("dotz"), None);
+        assert_eq!(result.get("dotz."), None);
+        assert_eq!(result.get("ddd"), None);
+        assert_eq!(result.get("dyn"), Some(&"mf"));
+    }
+
+    #[test]
+    fn test_create_indices() {
+        let opcodes = csv::StringRecord::from(vec![
+            "@raw".to_string(),
+            "@sample(path)".to_string(),
+            "@let(vel)".to_string(),
+        ]);
+        let mut opcode_indices = IndexMap::new();
+        let mut anno_indices = HashMap::new();
+        let mut let_indices = IndexMap::new();
+        create_indices(
+            opcodes,
+            &mut opcode_indices,
+            &mut anno_indices,
+            &mut let_indices,
+        );
+
+        assert_eq!(opcode_indices.len(), 2);
+        assert_eq!(anno_indices.len(), 2);
+        // `create_indices` keys `@raw` columns as `__raw_<column>`, which no opcode can clash with.
+        assert_eq!(opcode_indices.get("__raw_0"), Some(&0));
+        assert_eq!(opcode_indices.get("path"), Some(&1));
+        assert_eq!(anno_indices.get("raw"), Some(&0));
+        assert_eq!(anno_indices.get("sample"), Some(&1));
+        assert_eq!(let_indices.get("vel"), Some(&2));
+    }
+
+    #[test]
+    fn test_creat_header_ranges() {
+        let rows: Vec<Vec<String>> = vec![
+            vec!["".to_string(), "b".to_string()],
+            vec!["".to_string(), "d".to_string()],
             vec!["c".to_string(), "d".to_string()],
         ];
         let mut header_ranges = Vec::new();
@@ -673,8 +3927,25 @@ mod tests {
         .map(|s| s.to_string())
         .collect();
         let answers: Vec<String> = [
-            "4", "7", "", "4", "0", "-1", "0", "0", "0", "0", "", "0", "2", "3.142", "0", "1",
-            "127", "0.67", "0.5",
+            "4",
+            "7",
+            "${nrt(4, 2)}",
+            "4",
+            "0",
+            "-1",
+            "0",
+            "0",
+            "0",
+            "0",
+            "${atan2(0,0)}",
+            "0",
+            "2",
+            "3.142",
+            "0",
+            "1",
+            "127",
+            "0.67",
+            "0.5",
         ]
         .iter()
         .map(|s| s.to_string())
@@ -683,57 +3954,255 @@ mod tests {
             .into_iter()
             .collect::<HashMap<&str, &str>>();
 
-        let ctx = map_to_ctx(Some(&row_vars)).unwrap();
+        let ctx = map_to_ctx(&Project::default(), Some(&row_vars));
 
         for (cell, answer) in cells.iter_mut().zip(answers.iter()) {
-            let e = apply_expr(cell, Some(ctx.clone()));
-            assert!(e.is_ok());
+            let e = apply_expr(cell, ctx.clone());
+            assert_eq!(e.is_ok(), !answer.starts_with("${"));
             assert_eq!(cell, answer);
         }
     }
 
     #[test]
-    fn test_apply_expr() {
-        let mut cell = "This is ${v/l*127}.".to_string();
+    fn test_music_exprs() {
+        let cases = [
+            ("${round(db2amp(-6), 3)}", "0.501"),
+            ("${round(amp2db(0.5), 2)}", "-6.02"),
+            ("${db2amp(l-3)}", "1"),
+            ("${midi2hz(69)}", "440"),
+            ("${midi2hz(81)}", "880"),
+            ("${midi2hz(69, 442)}", "442"),
+            ("${round(hz2midi(261.63))}", "60"),
+            ("${cents2ratio(1200)}", "2"),
+            ("${ratio2cents(2)}", "1200"),
+            ("${semi2ratio(12)}", "2"),
+            ("${ratio2semi(4)}", "24"),
+            ("${semi2cents(1.5)}", "150"),
+            ("${cents2semi(-250)}", "-2.5"),
+            ("${clamp(200, 0, 127)}", "127"),
+            ("${clamp(1, 2, 0)}", "${clamp(1, 2, 0)}"),
+            ("${lerp(0, 10, 0.25)}", "2.5"),
+            ("${round(map(64, 0, 127, 0, 1), 2)}", "0.5"),
+            ("${map(1, 1, 1, 0, 1)}", "${map(1, 1, 1, 0, 1)}"),
+            ("${int(-3.7)}", "-3"),
+            ("${int(3.7)}", "3"),
+            ("${velcurve(127)}", "127"),
+            ("${velcurve(0)}", "0"),
+            ("${round(velcurve(64, 0))}", "64"),
+            ("${vel2amp(127)}", "1"),
+            ("${round(vel2amp(64), 2)}", "0.25"),
+            ("${vel2db(127)}", "0"),
+            ("${vel2db(0)}", "${vel2db(0)}"),
+            ("${2l}", "6"),
+            ("${gt(v, 1)}", "1"),
+            ("${ge(v, 3)}", "0"),
+            ("${lt(l, v)}", "0"),
+            ("${le(2, v)}", "1"),
+            ("${eq('mf', 'mf')}", "1"),
+            ("${ne('mf', 'ff')}", "1"),
+            ("${between(l, 1, 3)}", "1"),
+            ("${and(gt(l, 2), not(lt(v, 1)))}", "1"),
+            ("${or(0, 0)}", "0"),
+            ("${gt(1)}", "${gt(1)}"),
+        ];
         let row_vars = vec![("l", "3"), ("v", "2")]
             .into_iter()
             .collect::<HashMap<&str, &str>>();
 
-        let ctx = map_to_ctx(Some(&row_vars)).unwrap();
-        let e = apply_expr(&mut cell, Some(ctx));
+        let ctx = map_to_ctx(&Project::default(), Some(&row_vars));
 
-        assert!(e.is_ok());
-        assert_eq!(cell, format!("This is {:.2}.", 2. / 3. * 127.));
+        for (cell, answer) in cases {
+            let mut cell = cell.to_string();
+            let e = apply_expr(&mut cell, ctx.clone());
+            assert_eq!(e.is_ok(), !answer.starts_with("${"));
+            assert_eq!(cell, answer);
+        }
     }
 
     #[test]
-    fn test_expand_sample_csv() {
-        macro_rules! vec_str {
-            ($($s:expr),*) => (vec![$($s.to_string()),*]);
+    fn test_random_exprs() {
+        let row_vars = vec![("k", "60")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+        let ctx = map_to_ctx(&Project::default(), Some(&row_vars));
+        let eval = |cell: &str, seed: u64, key: &str, column: usize| {
+            let options = Options {
+                seed,
+                ..Default::default()
+            };
+            let mut cell = cell.to_string();
+            let _ = apply_expr(&mut cell, seed_ctx(&ctx, &options, key, column));
+            cell
+        };
+
+        let cell = "${rand()} ${randint(1, 4)} ${gauss(k, 2)}";
+        let a = eval(cell, 7, "./a.wav", 1);
+        assert_eq!(a, eval(cell, 7, "./a.wav", 1));
+        assert_ne!(a, eval(cell, 8, "./a.wav", 1));
+        assert_ne!(a, eval(cell, 7, "./b.wav", 1));
+        assert_ne!(a, eval(cell, 7, "./a.wav", 2));
+
+        for column in 0..100 {
+            let r = eval("${rand()}", 0, "./a.wav", column);
+            let r = r.parse::<f64>().unwrap();
+            assert!((0.0..1.0).contains(&r));
+            let i = eval("${randint(4, 1)}", 0, "./a.wav", column);
+            assert!(["1", "2", "3", "4"].contains(&i.as_str()));
         }
+        assert_eq!(eval("${rand(1)}", 0, "./a.wav", 0), "${rand(1)}");
+        let cell = "${randint(0.2, 0.8)}";
+        assert_eq!(eval(cell, 0, "./a.wav", 0), cell);
 
-        let mut sample_csv = SampleCSV {
-            opcode_indices: vec![("key", 0), ("sample", 1), ("_header", 3)]
-                .into_iter()
-                .map(|(k, v)| (k.to_string(), v))
-                .collect(),
-            rows: vec![
-                vec_str!["${k}", "./path/to/*.wav", "<regionA>"],
-                vec_str!["-1", "./path/to/sample1.wav", ""],
-                vec_str!["${k+v}", "./path/to/*{1,3,5}.wav", "<regionB>"],
-            ],
-            anno_indices: vec![("sample", 1), ("header", 3)]
-                .into_iter()
-                .map(|(k, v)| (k.to_string(), v))
-                .collect(),
-            header_ranges: vec![0..2, 2..3],
+        let relative = |root: &str, path: &str| {
+            let project = Project {
+                root: PathBuf::from(root),
+                ..Default::default()
+            };
+            project.relative(Path::new(path))
         };
+        assert_eq!(relative("/tmp/t2", "/tmp/t2/sub/a.csv"), "sub/a.csv");
+        assert_eq!(relative("t2", "t2/sub/a.csv"), "sub/a.csv");
+        assert_eq!(relative(".", "./sub/a.csv"), "sub/a.csv");
+    }
 
-        let sample_paths = (1..=5)
-            .map(|i| format!("./path/to/sample{i}.wav"))
-            .collect::<Vec<_>>();
-
-        let vars = vec![
+    #[test]
+    fn test_invalid_expr() {
+        let mut cell = "${k-*9} ${k} ${x}".to_string();
+        let row_vars = vec![("k", "60")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+
+        let ctx = map_to_ctx(&Project::default(), Some(&row_vars));
+        let e = apply_expr(&mut cell, ctx);
+
+        assert_eq!(cell, "${k-*9} 60 ${x}");
+        let Err(Error::Expr(errors)) = e else {
+            panic!("expected invalid expressions");
+        };
+        let exprs = errors.iter().map(|e| e.expr.as_str()).collect::<Vec<_>>();
+        assert_eq!(exprs, ["${k-*9}", "${x}"]);
+
+        let path = Path::new("test.csv");
+        let strict = Project {
+            options: Options {
+                strict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let copy = errors
+            .iter()
+            .map(|e| ExprError {
+                expr: e.expr.clone(),
+                message: e.message.clone(),
+            })
+            .collect();
+        let result = Err(Error::Expr(copy));
+        assert!(report_expr_errors(result, path, 27, 4, &strict).is_err());
+        let result = Err(Error::Expr(errors));
+        assert!(report_expr_errors(result, path, 27, 4, &strict).is_err());
+        assert_eq!(strict.reported.lock().unwrap().len(), 2);
+        let result = Err(Error::Expr(Vec::new()));
+        assert!(report_expr_errors(result, path, 27, 4, &Project::default()).is_ok());
+
+        assert_eq!(cell_name(0, 1), "A2");
+        assert_eq!(cell_name(27, 4), "AB5");
+
+        let row_vars = vec![("dyn", "mf")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+        let ctx = map_to_ctx(&Project::default(), Some(&row_vars));
+        let mut cell = "${dyn} ${dyn+1} ${-dyn} ${round(dyn*2)} ${eq(dyn, 'mf')}".to_string();
+        let Err(Error::Expr(errors)) = apply_expr(&mut cell, ctx) else {
+            panic!("expected invalid expressions");
+        };
+        assert_eq!(cell, "mf ${dyn+1} ${-dyn} ${round(dyn*2)} 1");
+        assert_eq!(
+            errors[0].message,
+            "the string `mf` can't be used in arithmetic"
+        );
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn test_tokenize_cell() {
+        use Token::*;
+
+        assert_eq!(tokenize_cell(""), []);
+        assert_eq!(tokenize_cell("key=60"), [Text("key=60")]);
+        assert_eq!(
+            tokenize_cell("a${k}b"),
+            [
+                Text("a"),
+                Expr {
+                    expr: "k",
+                    source: "${k}"
+                },
+                Text("b")
+            ]
+        );
+        assert_eq!(tokenize_cell("$${k} $$x"), [Text("${"), Text("k} $$x")]);
+        assert_eq!(
+            tokenize_cell("{${f({1})}}"),
+            [
+                Text("{"),
+                Expr {
+                    expr: "f({1})",
+                    source: "${f({1})}"
+                },
+                Text("}")
+            ]
+        );
+        assert_eq!(
+            tokenize_cell(r#"${f("}", '{\'')}"#),
+            [Expr {
+                expr: r#"f("}", '{\'')"#,
+                source: r#"${f("}", '{\'')}"#
+            }]
+        );
+        assert_eq!(tokenize_cell("x${k"), [Text("x"), Unclosed("${k")]);
+
+        let mut cell = "$${k} = ${k} ${k".to_string();
+        let row_vars = vec![("k", "60")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+        let e = apply_expr(&mut cell, map_to_ctx(&Project::default(), Some(&row_vars)));
+        assert!(e.is_err());
+        assert_eq!(cell, "${k} = 60 ${k");
+    }
+
+    #[test]
+    fn test_apply_expr() {
+        let mut cell = "This is ${v/l*127}.".to_string();
+        let row_vars = vec![("l", "3"), ("v", "2")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+
+        let ctx = map_to_ctx(&Project::default(), Some(&row_vars));
+        let e = apply_expr(&mut cell, ctx);
+
+        assert!(e.is_ok());
+        // `format_float` writes values with full precision, they are not rounded to 2 decimals.
+        assert_eq!(cell, format!("This is {}.", 2. / 3. * 127.));
+    }
+
+    #[test]
+    fn test_expand_sample_csv() {
+        let mut sample_csv = sample_csv(
+            &["key", "@sample", "@header"],
+            vec![
+                vec_str!["${k}", "./path/to/*.wav", "<regionA>"],
+                vec_str!["-1", "./path/to/sample1.wav", ""],
+                vec_str!["${k+v}", "./path/to/*{1,3,5}.wav", "<regionB>"],
+            ],
+            vec![0..2, 2..3],
+        );
+
+        let sample_paths = (1..=5)
+            .map(|i| format!("./path/to/sample{i}.wav"))
+            .collect::<Vec<_>>();
+
+        let vars = vec![
             ("./path/to/sample1.wav", vec![("k", "1"), ("v", "100")]),
             ("./path/to/sample2.wav", vec![("k", "2"), ("v", "100")]),
             ("./path/to/sample3.wav", vec![("k", "3"), ("v", "100")]),
@@ -745,7 +4214,13 @@ mod tests {
             .map(|(path, vars)| (path, vars.into_iter().collect()))
             .collect();
 
-        expand_sample_csv(&mut sample_csv, &sample_paths, &rows_vars).unwrap();
+        expand_sample_csv(
+            &mut sample_csv,
+            &sample_paths,
+            &rows_vars,
+            &Project::default(),
+        )
+        .unwrap();
 
         assert_eq!(sample_csv.rows.len(), 8);
 
@@ -770,4 +4245,1227 @@ mod tests {
 
         assert_eq!(actual_rows, expected_rows);
     }
+
+    #[test]
+    fn test_where() {
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@where"],
+            vec![vec_str![
+                "<region>",
+                "./*.wav",
+                "${and(between(k, 21, 108), le(rr, 4))}"
+            ]],
+            std::iter::once(0..1).collect(),
+        );
+        let sample_paths = vec_str![
+            "./k20_rr1.wav",
+            "./k21_rr1.wav",
+            "./k21_rr5.wav",
+            "./k108_rr4.wav"
+        ];
+        let rows_vars = sample_paths
+            .iter()
+            .map(|path| (path.as_str(), parse_sample_name(&path[2..path.len() - 4])))
+            .collect::<HashMap<_, _>>();
+
+        expand_sample_csv(
+            &mut sample_csv,
+            &sample_paths,
+            &rows_vars,
+            &Project::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            sample_csv
+                .rows
+                .iter()
+                .map(|row| row[1].as_str())
+                .collect::<Vec<_>>(),
+            ["./k21_rr1.wav", "./k108_rr4.wav"]
+        );
+    }
+
+    #[test]
+    fn test_expand_without_sample() {
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "volume"],
+            vec![
+                vec_str!["<control>", "", "${round(db2amp(0))}"],
+                vec_str!["<global>", "", "${-ceil(1.5)}"],
+                vec_str!["", "", "${x}"],
+            ],
+            vec![0..1, 1..3],
+        );
+
+        expand_sample_csv(&mut sample_csv, &[], &HashMap::new(), &Project::default()).unwrap();
+
+        assert_eq!(
+            sample_csv.rows,
+            vec![
+                vec_str!["<control>", "", "1"],
+                vec_str!["<global>", "", "-2"],
+                vec_str!["", "", "${x}"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_for_each() {
+        assert_eq!(ForEach::values("1..4").unwrap(), ["1", "2", "3"]);
+        assert_eq!(ForEach::values("1..=4, 1.5").unwrap(), ["1", "2.5", "4"]);
+        assert_eq!(
+            ForEach::values("0.1..=0.3, 0.1").unwrap(),
+            ["0.1", "0.2", "0.3"]
+        );
+        assert_eq!(ForEach::values("3..0").unwrap(), ["3", "2", "1"]);
+        assert_eq!(ForEach::values("C, 'D#', 2").unwrap(), ["C", "D#", "2"]);
+        assert!(ForEach::values("1..x").is_err());
+        assert!(ForEach::values("1..4, 0").is_err());
+        assert!(ForEach::values("0..1, 0.000001").is_err());
+
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@foreach(x)", "@foreach(y)", "locc64"],
+            vec![
+                vec_str!["<region>", "", "1..3", "a, b", "${x*10}_${y}"],
+                vec_str!["<region>", "", "", "", "${x}"],
+            ],
+            vec![0..1, 1..2],
+        );
+
+        let strict = Project {
+            options: Options {
+                strict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        expand_sample_csv(&mut sample_csv, &[], &HashMap::new(), &strict).unwrap_err();
+        sample_csv.rows.truncate(1);
+        sample_csv.header_ranges.truncate(1);
+        expand_sample_csv(&mut sample_csv, &[], &HashMap::new(), &strict).unwrap();
+
+        assert_eq!(
+            sample_csv
+                .rows
+                .iter()
+                .map(|row| row[4].as_str())
+                .collect::<Vec<_>>(),
+            ["10_a", "10_b", "20_a", "20_b"]
+        );
+
+        let for_each = ForEach::new(&sample_csv).unwrap();
+        let row = vec_str!["<region>", "", "0..300", "0..300", ""];
+        let location = (Path::new("test.csv"), 1);
+        assert!(for_each.bindings(&row, location, &strict.options).is_err());
+        let bindings = for_each.bindings(&row, location, &Options::default());
+        assert_eq!(bindings.unwrap().len(), 300);
+    }
+
+    #[test]
+    fn test_range_inheritance() {
+        let mut sample_csv = sample_csv(
+            &[
+                "@header",
+                "@name",
+                "@extends",
+                "@let(mic)",
+                "@let(offset)",
+                "@sample",
+                "volume",
+            ],
+            vec![
+                vec_str![
+                    "<region>",
+                    "close",
+                    "",
+                    "Close",
+                    "0",
+                    "./${mic}/*.wav",
+                    "${offset}"
+                ],
+                vec_str!["", "", "", "", "", "./${mic}/k62.wav", "${offset-1}"],
+                vec_str!["<region>", "far", "close", "Far", "-6", "", ""],
+                vec_str!["<region>", "", "far", "", "", "", "1"],
+                vec_str!["<region>", "loop", "loop", "", "", "", ""],
+            ],
+            vec![0..2, 2..3, 3..4, 4..5],
+        );
+        let project = Project::default();
+        let lines = |idx| {
+            range_rows(&sample_csv, idx, &mut Vec::new(), &project)
+                .unwrap()
+                .into_iter()
+                .map(|(line, row)| (line, row[6].clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lines(2),
+            [(1, String::from("1")), (2, String::from("${offset-1}"))]
+        );
+        assert_eq!(lines(3), [(5, String::new())]);
+        let mut template = sample_csv.clone();
+        template.rows[0][0] = String::from("#<region>");
+        let rows = |idx| range_rows(&template, idx, &mut Vec::new(), &project).unwrap();
+        assert!(rows(0).is_empty());
+        assert_eq!(rows(1)[0].1[0], "<region>");
+        assert_eq!(rows(1).len(), 2);
+        let strict = Project {
+            options: Options {
+                strict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(range_rows(&sample_csv, 3, &mut Vec::new(), &strict).is_err());
+
+        sample_csv.rows.truncate(3);
+        sample_csv.header_ranges.truncate(2);
+        let sample_paths = vec_str!["./Close/k60.wav", "./Close/k62.wav", "./Far/k62.wav"];
+        expand_sample_csv(
+            &mut sample_csv,
+            &sample_paths,
+            &HashMap::new(),
+            &Project::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            sample_csv
+                .rows
+                .iter()
+                .map(|row| format!("{} {}", row[5], row[6]))
+                .collect::<Vec<_>>(),
+            [
+                "./Close/k60.wav 0",
+                "./Close/k62.wav -1",
+                "./Far/k62.wav -7"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled_rows() {
+        let rows = vec![
+            vec_str!["<region>", "./*.wav", "", "a", "0"],
+            vec_str!["#", "./a.wav", "", "", "1"],
+            vec_str!["", "./b.wav", "${lite}", "", "2"],
+            vec_str!["#<region>", "./*.wav", "", "", "3"],
+            vec_str!["", "./c.wav", "", "", "4"],
+            vec_str!["<region>", "./*.wav", "No", "", "5"],
+        ];
+        let mut header_ranges = Vec::new();
+        creat_header_ranges(&rows, &mut header_ranges, &0);
+        assert_eq!(header_ranges, vec![0..3, 3..5, 5..6]);
+
+        let sample_csv = sample_csv(
+            &["@header", "@sample", "@enabled", "@note(why)", "volume"],
+            rows,
+            header_ranges,
+        );
+        assert_eq!(titles_of(&sample_csv.titles, "note"), [3]);
+        let mut project = Project::default();
+        let volumes = |project: &Project| {
+            (0..3)
+                .flat_map(|idx| range_rows(&sample_csv, idx, &mut Vec::new(), project).unwrap())
+                .map(|(line, row)| format!("{}:{}", line, row[4]))
+                .collect::<Vec<_>>()
+        };
+        project.globals.insert(String::from("lite"), 1.0);
+        assert_eq!(volumes(&project), ["1:0", "3:2"]);
+        project.globals.insert(String::from("lite"), 0.0);
+        assert_eq!(volumes(&project), ["1:0"]);
+
+        assert!(is_commented(&[String::from("#defined")]));
+        assert!(!is_commented(&[String::from("#define $KEY 60")]));
+        assert!(!is_commented(&[String::from(" #include \"common.sfz\"")]));
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut sample_csv = SampleCSV {
+            path: PathBuf::from("dir/test.csv"),
+            ..sample_csv(
+                &["@header", "@sample", "@comment", "volume"],
+                vec![
+                    vec_str!["<group>", "", "Piano ${1+1}", ""],
+                    vec_str!["<region>", "./*.wav", "", "0"],
+                ],
+                vec![0..1, 1..2],
+            )
+        };
+        let project = Project {
+            options: Options {
+                comments: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let sample_paths = vec_str!["./a.wav"];
+        expand_sample_csv(&mut sample_csv, &sample_paths, &HashMap::new(), &project).unwrap();
+        let banner = format!(
+            "// Generated by csv2sfz {} from test.csv\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(
+            render_sfz(&sample_csv),
+            banner
+                + "// test.csv:2\n<group> // Piano 2\n"
+                + "// test.csv:3\n<region> sample=./a.wav volume=0 \n"
+        );
+
+        sample_csv.titles[2] = String::from("@comment(before)");
+        sample_csv.rows.drain(..2);
+        assert_eq!(
+            render_sfz(&sample_csv),
+            "// Piano 2\n<group> \n// test.csv:3\n<region> sample=./a.wav volume=0 \n"
+        );
+
+        sample_csv.rows[0][2] = String::from("Piano\r\n  soft <region> ");
+        assert!(render_sfz(&sample_csv).starts_with("// Piano\n// soft <region>\n<group> \n"));
+        sample_csv.titles[2] = String::from("@comment");
+        assert!(render_sfz(&sample_csv).starts_with("<group> // Piano\n// soft <region>\n"));
+    }
+
+    #[test]
+    fn test_default_path() {
+        let mut controlled = sample_csv(
+            &["@header", "@sample", "set_cc1"],
+            vec![
+                vec_str!["<control>", "", "64"],
+                vec_str!["<region>", "./Piano/*/*.wav", ""],
+            ],
+            vec![0..1, 1..2],
+        );
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample"],
+            vec![
+                vec_str!["<region>", "\"./Piano/Close/*.wav\""],
+                vec_str!["<region>", "// ./Piano/Room/*.wav"],
+                vec_str!["<region>", "./Piano/Clé/*.wav"],
+            ],
+            vec![0..1, 1..2, 2..3],
+        );
+        let project = Project {
+            options: Options {
+                default_path: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let sample_paths = vec_str![
+            "./Piano/Close/a.wav",
+            "./Piano/Clé/b.wav",
+            "./Piano/Room/c.wav"
+        ];
+        let mut factored = sample_csv.clone();
+        expand_sample_csv(&mut factored, &sample_paths, &HashMap::new(), &project).unwrap();
+        assert_eq!(
+            render_sfz(&factored),
+            "<control> default_path=\"./Piano/\" \n<region> sample=\"Close/a.wav\" \n\
+             <region> \n<region> sample=Clé/b.wav \n"
+        );
+
+        expand_sample_csv(&mut controlled, &sample_paths, &HashMap::new(), &project).unwrap();
+        assert_eq!(
+            render_sfz(&controlled),
+            "<control> set_cc1=64 default_path=./Piano/ \n<region> sample=Close/a.wav \n\
+             <region> sample=Clé/b.wav \n<region> sample=Room/c.wav \n"
+        );
+
+        sample_csv.rows.truncate(1);
+        sample_csv.header_ranges.truncate(1);
+        sample_csv.rows[0][1] = String::from("./*/*.wav");
+        let sample_paths = vec_str!["./a/x.wav", "./b/y.wav"];
+        expand_sample_csv(&mut sample_csv, &sample_paths, &HashMap::new(), &project).unwrap();
+        assert_eq!(
+            render_sfz(&sample_csv),
+            "<region> sample=./a/x.wav \n<region> sample=./b/y.wav \n"
+        );
+        assert!(!sample_csv.opcode_indices.contains_key("default_path"));
+    }
+
+    #[test]
+    fn test_group_opcodes() {
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "key", "volume"],
+            vec![
+                vec_str!["<region>", "./*.wav", "${k}", "-3"],
+                vec_str!["<region>", "./a_k1.wav", "", ""],
+            ],
+            vec![0..1, 1..2],
+        );
+        let project = Project {
+            options: Options {
+                group_opcodes: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let sample_paths = vec_str!["./a_k1.wav", "./a_k2.wav"];
+        let rows_vars = sample_paths
+            .iter()
+            .map(|path| (path.as_str(), parse_sample_name(&path[2..path.len() - 4])))
+            .collect::<HashMap<_, _>>();
+        let mut grouped = sample_csv.clone();
+        expand_sample_csv(&mut grouped, &sample_paths, &rows_vars, &project).unwrap();
+        assert_eq!(
+            render_sfz(&grouped),
+            "<group> volume=-3 \n<region> sample=./a_k1.wav key=1 \n\
+             <region> sample=./a_k2.wav key=2 \n<group> \n<region> sample=./a_k1.wav \n"
+        );
+
+        sample_csv.rows[1][0] = String::from("<group>");
+        expand_sample_csv(&mut sample_csv, &sample_paths, &rows_vars, &project).unwrap();
+        assert_eq!(
+            render_sfz(&sample_csv),
+            "<region> sample=./a_k1.wav key=1 volume=-3 \n\
+             <region> sample=./a_k2.wav key=2 volume=-3 \n<group> sample=./a_k1.wav \n"
+        );
+    }
+
+    #[test]
+    fn test_let_columns() {
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@let(vel)", "@let(lo)", "lovel"],
+            vec![
+                vec_str!["<region>", "./path/*.wav", "${lo*2}", "${k}", "${vel}"],
+                vec_str!["", "./path/sample1.wav", "", "${k+10}", "${vel}"],
+            ],
+            std::iter::once(0..2).collect(),
+        );
+        let sample_paths = vec_str!["./path/sample1.wav", "./path/sample2.wav"];
+        let rows_vars: HashMap<&str, HashMap<&str, &str>> = vec![
+            ("./path/sample1.wav", vec![("k", "1")]),
+            ("./path/sample2.wav", vec![("k", "2")]),
+        ]
+        .into_iter()
+        .map(|(path, vars)| (path, vars.into_iter().collect()))
+        .collect();
+
+        expand_sample_csv(
+            &mut sample_csv,
+            &sample_paths,
+            &rows_vars,
+            &Project::default(),
+        )
+        .unwrap();
+
+        let lovels = sample_csv
+            .rows
+            .iter()
+            .map(|row| (row[1].as_str(), row[4].as_str()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(lovels.get("./path/sample1.wav"), Some(&"22"));
+        assert_eq!(lovels.get("./path/sample2.wav"), Some(&"4"));
+
+        let let_indices = sample_csv.let_indices;
+        let row = vec_str!["", "", "${lo}", "${vel}", ""];
+        assert!(let_order(&let_indices, &row).is_err());
+        let row = vec_str!["", "", "${lo}", "1", ""];
+        assert_eq!(let_order(&let_indices, &row), Ok(vec![3, 2]));
+        let row = vec_str![
+            "",
+            "",
+            "${lookup('dyn', dyn, \"lo\")}",
+            "${lo+12} ${vel (1)}",
+            ""
+        ];
+        assert_eq!(let_order(&let_indices, &row), Ok(vec![2, 3]));
+        assert_eq!(
+            math_expr::variables("lookup('k\\'s', 1e3, \"x\") + db2amp (v) - rel_k2"),
+            ["v", "rel_k2"]
+        );
+    }
+
+    #[test]
+    fn test_let_shadowing() {
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@let(k)", "key"],
+            vec![vec_str!["<region>", "./path/*.wav", "${k+12}", "${k}"]],
+            std::iter::once(0..1).collect(),
+        );
+        let strict = Project {
+            options: Options {
+                strict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let sample_paths = vec_str!["./path/a_k1.wav", "./path/a_k2.wav"];
+        let rows_vars = sample_paths
+            .iter()
+            .map(|path| (path.as_str(), parse_sample_name(&path[7..path.len() - 4])))
+            .collect::<HashMap<_, _>>();
+        expand_sample_csv(&mut sample_csv, &sample_paths, &rows_vars, &strict).unwrap();
+        let keys = sample_csv
+            .rows
+            .iter()
+            .map(|row| row[3].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["13", "14"]);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let text = "# comment\ntop = 1\n\n[define]\n  tuning = -12 \nlayers=4\n";
+        let entries = parse_config(text).unwrap();
+        let entries = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.section.as_str(),
+                    e.name.as_str(),
+                    e.value.as_str(),
+                    e.line,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                ("", "top", "1", 2),
+                ("define", "tuning", "-12", 5),
+                ("define", "layers", "4", 6)
+            ]
+        );
+        assert!(matches!(parse_config("[define]\nx"), Err(Error::Config(2))));
+    }
+
+    #[test]
+    fn test_load_globals() {
+        let defines = [
+            ("sheet.csv:A2", "layers", "3"),
+            ("sheet.csv:A3", "tuning", "-12"),
+            ("csv2sfz.conf:2", "layers", "4"),
+            ("csv2sfz.conf:3", "step", "round(127/layers)"),
+            ("csv2sfz.conf:4", "bad", "1+"),
+            ("--define", "tuning", "tuning+1"),
+        ]
+        .map(|(source, name, value)| Define {
+            source: source.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+
+        let globals = load_globals(&defines, &Project::default()).unwrap();
+        assert_eq!(
+            globals.into_iter().collect::<Vec<_>>(),
+            [
+                ("layers".to_string(), 4.0),
+                ("tuning".to_string(), -11.0),
+                ("step".to_string(), 32.0),
+            ]
+        );
+
+        let strict = Project {
+            options: Options {
+                strict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(load_globals(&defines, &strict).is_err());
+
+        let project = Project {
+            globals: vec![("k".to_string(), 1.0), ("l".to_string(), 2.0)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let row_vars = vec![("k", "60")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+        let mut cell = "${k} ${l}".to_string();
+        apply_expr(&mut cell, map_to_ctx(&project, Some(&row_vars))).unwrap();
+        assert_eq!(cell, "60 2");
+    }
+
+    #[test]
+    fn test_lookup() {
+        let table = Table {
+            columns: vec!["lovel".to_string(), "hivel".to_string(), "name".to_string()],
+            rows: [
+                ("pp", ["1", "40", "soft"]),
+                ("ff", ["91", "127", ""]),
+                ("64", ["64", "", ""]),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.map(String::from).to_vec()))
+            .collect(),
+        };
+        let project = Project {
+            tables: Arc::new([("dyn".to_string(), table)].into_iter().collect()),
+            ..Default::default()
+        };
+        let row_vars = vec![("dyn", "ff"), ("k", "64")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+        let ctx = map_to_ctx(&project, Some(&row_vars));
+
+        let cases = [
+            ("${lookup('dyn', dyn, 'lovel')}", "91"),
+            (r#"${lookup("dyn", "pp", 2) + 1}"#, "41"),
+            ("${lookup('dyn', 'pp', 'name')}", "soft"),
+            ("${lookup('dyn', k, 'lovel')}", "64"),
+            ("${dyn}", "ff"),
+            (r#"${"it's"}"#, "it's"),
+            (
+                "${lookup('dyn', 'mf', 'lovel')}",
+                "${lookup('dyn', 'mf', 'lovel')}",
+            ),
+            (
+                "${lookup('dyn', 'ff', 'name')}",
+                "${lookup('dyn', 'ff', 'name')}",
+            ),
+            (
+                "${lookup('dyn', 'pp', 'vel')}",
+                "${lookup('dyn', 'pp', 'vel')}",
+            ),
+            ("${lookup('vel', 'pp', 1)}", "${lookup('vel', 'pp', 1)}"),
+            ("${lookup(dyn)}", "${lookup(dyn)}"),
+        ];
+        for (expr, answer) in cases {
+            let mut cell = expr.to_string();
+            let e = apply_expr(&mut cell, ctx.clone());
+            assert_eq!(cell, answer, "{}", expr);
+            assert_eq!(e.is_ok(), !answer.starts_with("${"), "{}", expr);
+        }
+
+        let mut cell = "${lookup('dyn', 'mf', 1)}".to_string();
+        let Err(Error::Expr(errors)) = apply_expr(&mut cell, ctx) else {
+            panic!("expected an expression error");
+        };
+        assert_eq!(errors[0].message, r#"lookup("dyn"): No row `mf`"#);
+    }
+
+    #[test]
+    fn test_load_functions() {
+        let decls = [
+            ("fn.csv:A2", "vel(x, n)", "floor(nl(x/n)*127)"),
+            ("fn.csv:A3", "twice(x)", "2*x"),
+            ("fn.csv:A4", "loop(x)", "loop(x)+1"),
+            ("fn.csv:A5", "bad(x,)", "1"),
+            ("fn.csv:A6", "broken(x)", "x+"),
+            ("csv2sfz.conf:2", "twice(x)", "x*2+layers"),
+            ("csv2sfz.conf:3", "pan2(x)", "twice(x)-k"),
+            ("csv2sfz.conf:4", "zero()", "0"),
+        ]
+        .map(|(source, name, value)| Define {
+            source: source.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+
+        let mut project = Project::default();
+        let functions = load_functions(&decls, &project).unwrap();
+        assert_eq!(
+            functions
+                .iter()
+                .map(|f| f.name.as_str())
+                .collect::<Vec<_>>(),
+            ["vel", "twice", "loop", "pan2", "zero"]
+        );
+        project.functions = functions;
+        project.globals = std::iter::once(("layers".to_string(), 3.0)).collect();
+        let row_vars = vec![("k", "60")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+        let ctx = map_to_ctx(&project, Some(&row_vars));
+
+        let cases = [
+            ("${vel(2, 3)}", "102"),
+            ("${twice(k)}", "123"),
+            ("${zero()}", "0"),
+            ("${vel(1)}", "${vel(1)}"),
+            ("${loop(1)}", "${loop(1)}"),
+            ("${pan2(1)}", "${pan2(1)}"),
+        ];
+        for (expr, answer) in cases {
+            let mut cell = expr.to_string();
+            let e = apply_expr(&mut cell, ctx.clone());
+            assert_eq!(cell, answer, "{}", expr);
+            assert_eq!(e.is_ok(), !answer.starts_with("${"), "{}", expr);
+        }
+
+        let mut cell = "${pan2(1)}".to_string();
+        let Err(Error::Expr(errors)) = apply_expr(&mut cell, ctx) else {
+            panic!("expected an expression error");
+        };
+        assert_eq!(
+            errors[0].message,
+            "in `pan2` defined at csv2sfz.conf:3: Variable 'k' is not defined"
+        );
+
+        project.options.strict = true;
+        assert!(load_functions(&decls, &project).is_err());
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let mut options = Options::default();
+        options.add_function("mic", |args| match args {
+            [Value::String(name)] if name == "close" => Ok(Value::Number(1.0)),
+            [Value::String(name)] => Err(format!("Unknown mic `{}`", name)),
+            _ => Err(String::from("Expected a mic name")),
+        });
+        options.add_function("label2", |args| {
+            Ok(Value::String(format!("{:?}", args.first())))
+        });
+
+        unsafe extern "C" fn sum(
+            user_data: *mut c_void,
+            argc: usize,
+            numbers: *const f64,
+            strings: *const *const c_char,
+            result: *mut f64,
+        ) -> c_int {
+            let offset = unsafe { *(user_data as *const f64) };
+            let numbers = unsafe { std::slice::from_raw_parts(numbers, argc) };
+            let strings = unsafe { std::slice::from_raw_parts(strings, argc) };
+            if strings.iter().any(|s| !s.is_null()) {
+                return 1;
+            }
+            unsafe { *result = offset + numbers.iter().sum::<f64>() };
+            0
+        }
+        let mut offset = 0.5;
+        let name = std::ffi::CString::new("sum").unwrap();
+        unsafe {
+            csv2sfz_options_add_function(
+                &mut options,
+                name.as_ptr(),
+                Some(sum),
+                &mut offset as *mut f64 as *mut c_void,
+            )
+        };
+
+        let project = Project {
+            options,
+            ..Default::default()
+        };
+        let row_vars = vec![("mic", "close")]
+            .into_iter()
+            .collect::<HashMap<&str, &str>>();
+        let ctx = map_to_ctx(&project, Some(&row_vars));
+        let cases = [
+            ("${mic(mic)}", "1"),
+            ("${label2(3)}", "Some(Number(3.0))"),
+            ("${sum(1, 2, 3)}", "6.5"),
+            ("${sum()}", "0.5"),
+            ("${mic('far')}", "${mic('far')}"),
+            ("${sum('far')}", "${sum('far')}"),
+        ];
+        for (expr, answer) in cases {
+            let mut cell = expr.to_string();
+            let e = apply_expr(&mut cell, ctx.clone());
+            assert_eq!(cell, answer, "{}", expr);
+            assert_eq!(e.is_ok(), !answer.starts_with("${"), "{}", expr);
+        }
+
+        let mut cell = "${mic('far')}".to_string();
+        let Err(Error::Expr(errors)) = apply_expr(&mut cell, ctx) else {
+            panic!("expected an expression error");
+        };
+        assert_eq!(errors[0].message, "`mic`: Unknown mic `far`");
+    }
+
+    #[test]
+    fn test_range_samples() {
+        let paths = [
+            "./P_k36_v1_mic=close.wav",
+            "./P_k36_v2_mic=close.wav",
+            "./P_k40_v1_mic=close.wav",
+            "./P_k40_v2_mic=close.wav",
+            "./P_k40_v3_mic=close.wav",
+            "./P_k43_v1_mic=far.wav",
+        ]
+        .map(String::from);
+        let rows_vars = paths
+            .iter()
+            .map(|path| (path.as_str(), parse_sample_name(path)))
+            .collect::<HashMap<_, _>>();
+        let samples = Arc::new(RangeSamples::new(paths.iter(), &rows_vars));
+
+        let cases = [
+            (0, "${count()} ${count(k)} ${count(mic)}", "6 2 5"),
+            (0, "${minof(k)} ${maxof(k)} ${maxof(v, k)}", "36 43 2"),
+            (3, "${rank(k)} ${rank(v, k)} ${rank(v)}", "2 2 2"),
+            (0, "${prev(k)} ${next(k)}", "36 40"),
+            (3, "${prev(k)} ${next(k)}", "36 43"),
+            (5, "${prev(k)} ${next(k)} ${prev(k, mic)}", "40 43 43"),
+            (0, "${rank(k+1)}", "${rank(k+1)}"),
+            (0, "${rank(mic)}", "${rank(mic)}"),
+            (0, "${minof(l)}", "${minof(l)}"),
+            (0, "${count(l)}", "${count(l)}"),
+            (0, "${next(k, v, mic)}", "${next(k, v, mic)}"),
+        ];
+        for (sample, expr, answer) in cases {
+            let key = paths[sample].as_str();
+            let mut ctx = map_to_ctx(&Project::default(), rows_vars.get(key));
+            samples.register(&mut ctx, key);
+            let mut cell = expr.to_string();
+            let e = apply_expr(&mut cell, ctx);
+            assert_eq!(cell, answer, "{}", expr);
+            assert_eq!(e.is_ok(), !answer.starts_with("${"), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_key_spread() {
+        let mut sample_csv = sample_csv(
+            &["@header", "@keyspread(up)", "hikey"],
+            Vec::new(),
+            Vec::new(),
+        );
+        let key_spread = KeySpread::new(&mut sample_csv, &Options::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(key_spread.policy, SplitPolicy::Up);
+        assert_eq!((key_spread.lokey, key_spread.hikey), (3, 2));
+        assert_eq!(sample_csv.titles[3], "lokey");
+
+        let rows = vec![
+            vec_str!["<group>", "", "", ""],
+            vec_str!["<region>", "43", "", ""],
+            vec_str!["<region>", "36", "", ""],
+            vec_str!["<region>", "39.2", "", ""],
+            vec_str!["<region>", "36", "40", ""],
+        ];
+        let cases = [
+            (
+                SplitPolicy::Midpoint,
+                [
+                    ("", ""),
+                    ("127", "42"),
+                    ("37", "0"),
+                    ("41", "38"),
+                    ("40", "0"),
+                ],
+            ),
+            (
+                SplitPolicy::Up,
+                [
+                    ("", ""),
+                    ("127", "43"),
+                    ("38", "36"),
+                    ("42", "39"),
+                    ("40", "36"),
+                ],
+            ),
+            (
+                SplitPolicy::Down,
+                [
+                    ("", ""),
+                    ("43", "40"),
+                    ("36", "0"),
+                    ("39", "37"),
+                    ("40", "0"),
+                ],
+            ),
+        ];
+        for (policy, answer) in cases {
+            let key_spread = KeySpread {
+                policy,
+                ..key_spread
+            };
+            let mut rows = rows.clone();
+            key_spread
+                .apply(&mut rows, (Path::new("test.csv"), 1), &Project::default())
+                .unwrap();
+            let result = rows
+                .iter()
+                .map(|row| (row[2].as_str(), row[3].as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(result, answer, "{:?}", policy);
+        }
+
+        let mut rows = vec![vec_str!["<region>", "C4", "", ""]];
+        let strict = Project {
+            options: Options {
+                strict: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(
+            key_spread
+                .apply(&mut rows, (Path::new("test.csv"), 1), &strict)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_vel_spread() {
+        let mut sample_csv = sample_csv(
+            &["@header", "key", "@velspread(even, xf=10)"],
+            Vec::new(),
+            Vec::new(),
+        );
+        let vel_spread = VelSpread::new(&mut sample_csv, &Options::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(vel_spread.split, VelSplit::Even);
+        assert_eq!(vel_spread.group, Some(1));
+        assert_eq!((vel_spread.lovel, vel_spread.hivel), (3, 4));
+        assert_eq!(vel_spread.xf, Some([5, 6, 7, 8]));
+
+        let mut rows = vec![
+            vec_str!["<group>", "", "", "", "", "", "", "", ""],
+            vec_str!["<region>", "36", "3", "", "", "", "", "", ""],
+            vec_str!["<region>", "36", "1", "", "", "", "", "", ""],
+            vec_str!["<region>", "36", "2", "", "", "", "", "", ""],
+            vec_str!["<region>", "40", "5", "", "120", "", "", "", ""],
+        ];
+        vel_spread
+            .apply(&mut rows, (Path::new("test.csv"), 1), &Project::default())
+            .unwrap();
+        assert_eq!(
+            rows.into_iter()
+                .map(|row| row[3..].join(" "))
+                .collect::<Vec<_>>(),
+            [
+                "     ",
+                "81 127 81 90  ",
+                "1 47   38 47",
+                "38 90 38 47 81 90",
+                "1 120    ",
+            ]
+        );
+
+        let cases = [
+            (VelSplit::Even, 4, Some(vec![32, 64, 95, 127])),
+            (VelSplit::Nl(0.0), 2, Some(vec![64, 127])),
+            (VelSplit::Nl(-2.0), 2, Some(vec![85, 127])),
+            (VelSplit::Bounds(vec![40, 90]), 3, Some(vec![40, 90, 127])),
+            (VelSplit::Bounds(vec![40, 90]), 2, None),
+            (VelSplit::Even, 1, Some(vec![127])),
+            (VelSplit::Bounds(vec![40, 40]), 3, None),
+            (VelSplit::Even, 128, None),
+            (VelSplit::Nl(-30.0), 3, None),
+        ];
+        for (split, n, answer) in cases {
+            let vel_spread = VelSpread {
+                split,
+                ..VelSpread::new(&mut sample_csv, &Options::default())
+                    .unwrap()
+                    .unwrap()
+            };
+            assert_eq!(vel_spread.bounds(n).ok(), answer, "{:?}", vel_spread.split);
+        }
+
+        sample_csv.titles[2] = String::from("@velspread(90, 40)");
+        let strict = Options {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(VelSpread::new(&mut sample_csv, &strict).is_err());
+        sample_csv.titles[2] = String::from("@velspread(40, 40)");
+        assert!(VelSpread::new(&mut sample_csv, &strict).is_err());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        assert!(natural_cmp("rr2.wav", "rr10.wav").is_lt());
+        assert!(natural_cmp("v1_rr3", "v1_rr03").is_gt());
+        assert!(natural_cmp("a", "a1").is_lt());
+        assert!(natural_cmp("b1", "a2").is_gt());
+
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@rr", "seq_length"],
+            Vec::new(),
+            Vec::new(),
+        );
+        let round_robin = RoundRobin::new(&mut sample_csv, &Options::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(round_robin.opcodes, [3, 4]);
+
+        let rows = vec![
+            vec_str!["<group>", "", "", "", ""],
+            vec_str!["<region>", "./v1_rr10.wav", "1", "", ""],
+            vec_str!["<region>", "./v1_rr2.wav", "1", "", ""],
+            vec_str!["<region>", "./v2_rr1.wav", "2", "", ""],
+            vec_str!["<region>", "./v1_rr1.wav", "1", "", ""],
+            vec_str!["<region>", "./v3_rr1.wav", "3", "1", "1"],
+        ];
+        let mut seq_rows = rows.clone();
+        round_robin.apply(&mut seq_rows);
+        assert_eq!(
+            seq_rows
+                .iter()
+                .map(|row| row[3..].join(" "))
+                .collect::<Vec<_>>(),
+            [" ", "3 3", "3 2", "1 1", "3 1", "1 1"]
+        );
+
+        let round_robin = RoundRobin {
+            random: true,
+            ..round_robin
+        };
+        let mut random_rows = rows;
+        round_robin.apply(&mut random_rows);
+        assert_eq!(
+            random_rows
+                .iter()
+                .map(|row| row[3..].join(" "))
+                .collect::<Vec<_>>(),
+            [" ", "0.6667 1", "0.3333 0.6667", "0 1", "0 0.3333", "1 1"]
+        );
+    }
+
+    #[test]
+    fn test_release() {
+        let mut sheet = sample_csv(
+            &["@header", "@sample", "@release(k, v)"],
+            Vec::new(),
+            Vec::new(),
+        );
+        let release = Release::new(&mut sheet, &Options::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(release.vars, ["k", "v"]);
+        assert_eq!(release.trigger, 3);
+
+        let sample_paths = vec_str![
+            "./s/k=60_v=1.wav",
+            "./s/k=60_v=2.wav",
+            "./r/k=60_v=1.wav",
+            "./r/k=60_v=1_alt.wav",
+            "./r/k=62_v=2.wav"
+        ];
+        let rows_vars = sample_paths
+            .iter()
+            .map(|path| {
+                let name = Path::new(path).file_stem().unwrap().to_str().unwrap();
+                (path.as_str(), parse_sample_name(name))
+            })
+            .collect::<HashMap<_, _>>();
+        let row = vec_str!["<region>", "./s/*.wav", "./r/*.wav", ""];
+        let expand = |pattern: &str, row: &[String]| {
+            try_get_matcher(pattern)
+                .map(|matcher| matching_paths(&sample_paths, 1, row, matcher, |_| {}))
+        };
+        let primaries = expand("./s/*.wav", &row).unwrap();
+        let mut releases = release
+            .pair(
+                &row,
+                &primaries,
+                expand,
+                &rows_vars,
+                (Path::new("test.csv"), 2),
+            )
+            .unwrap();
+        releases.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            releases
+                .iter()
+                .map(|r| format!("{} {}", r.primary, r.row.join(" ")))
+                .collect::<Vec<_>>(),
+            [
+                "./s/k=60_v=1.wav <region> ./r/k=60_v=1.wav  release",
+                "./s/k=60_v=1.wav <region> ./r/k=60_v=1_alt.wav  release",
+            ]
+        );
+
+        let mut ctx = mexprp::Context::new();
+        release.bind(&mut ctx, rows_vars.get("./r/k=62_v=2.wav"));
+        assert_eq!(math_expr::eval("rel_k+rel_v", &ctx).unwrap(), "64");
+
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@release(k, v)", "@rr"],
+            vec![vec_str!["<region>", "./s/*_v=1.wav", "./r/*.wav", "${k}"]],
+            std::iter::once(0..1).collect(),
+        );
+        expand_sample_csv(
+            &mut sample_csv,
+            &sample_paths,
+            &rows_vars,
+            &Project::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            render_sfz(&sample_csv),
+            "<region> sample=./s/k=60_v=1.wav seq_length=1 seq_position=1 \n\
+             <region> sample=./r/k=60_v=1.wav trigger=release seq_length=2 seq_position=1 \n\
+             <region> sample=./r/k=60_v=1_alt.wav trigger=release seq_length=2 seq_position=2 \n"
+        );
+    }
+
+    #[test]
+    fn test_shared_release() {
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@release(k)", "pitch_keycenter"],
+            vec![vec_str!["<region>", "./s/*.wav", "./r/*.wav", "${k}"]],
+            std::iter::once(0..1).collect(),
+        );
+        let sample_paths = vec_str!["./s/k=60_v=1.wav", "./s/k=60_v=2.wav", "./r/k=60.wav"];
+        let rows_vars = sample_paths
+            .iter()
+            .map(|path| {
+                let name = Path::new(path).file_stem().unwrap().to_str().unwrap();
+                (path.as_str(), parse_sample_name(name))
+            })
+            .collect::<HashMap<_, _>>();
+        expand_sample_csv(
+            &mut sample_csv,
+            &sample_paths,
+            &rows_vars,
+            &Project::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            render_sfz(&sample_csv),
+            "<region> sample=./s/k=60_v=1.wav pitch_keycenter=60 \n\
+             <region> sample=./r/k=60.wav pitch_keycenter=60 trigger=release \n\
+             <region> sample=./s/k=60_v=2.wav pitch_keycenter=60 \n\
+             <region> sample=./r/k=60.wav pitch_keycenter=60 trigger=release \n"
+        );
+    }
+
+    #[test]
+    fn test_merge_key() {
+        let mut plain = sample_csv(
+            &["@header", "@sample", "lokey"],
+            vec![
+                vec_str!["<region>", "./a_k=60.wav", "0"],
+                vec_str!["", "./b_k=60.wav", "61"],
+            ],
+            std::iter::once(0..2).collect(),
+        );
+        let sample_paths = vec_str!["./a_k=60.wav", "./b_k=60.wav"];
+        expand_sample_csv(
+            &mut plain,
+            &sample_paths,
+            &HashMap::new(),
+            &Project::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            render_sfz(&plain),
+            "<region> sample=./a_k=60.wav lokey=0 \nsample=./b_k=60.wav lokey=61 \n"
+        );
+
+        let mut sample_csv = sample_csv(
+            &["@header", "@sample", "@key", "lokey"],
+            Vec::new(),
+            Vec::new(),
+        );
+        let merge_key = MergeKey::new(&sample_csv).unwrap();
+        assert_eq!(merge_key.names, ["sample"]);
+
+        let rows_vars = ["./a_k=60.wav", "./b_k=60.wav"]
+            .into_iter()
+            .map(|path| (path, parse_sample_name(&path[2..path.len() - 4])))
+            .collect::<HashMap<_, _>>();
+        let rows = vec![
+            ("./a_k=60.wav", vec_str!["", "./a_k=60.wav", "lo", "0"]),
+            ("./b_k=60.wav", vec_str!["", "./b_k=60.wav", "lo", "1"]),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect::<HashMap<_, _>>();
+        assert_eq!(
+            merge_key.rekey(&sample_csv, rows.clone(), &rows_vars).len(),
+            2
+        );
+
+        sample_csv.titles[2] = String::from("@key(k)");
+        let merge_key = MergeKey::new(&sample_csv).unwrap();
+        let keyed = merge_key.rekey(&sample_csv, rows.clone(), &rows_vars);
+        assert_eq!(
+            keyed.into_values().collect::<Vec<_>>(),
+            [vec_str!["", "./b_k=60.wav", "lo", "1"]]
+        );
+
+        sample_csv.titles[2] = String::from("@key(k, lokey)");
+        let merge_key = MergeKey::new(&sample_csv).unwrap();
+        assert_eq!(merge_key.rekey(&sample_csv, rows, &rows_vars).len(), 2);
+
+        sample_csv.titles[2] = String::from("@key");
+        sample_csv.rows = vec![
+            vec_str!["<region>", "./a_k=60.wav", "lo", "0"],
+            vec_str!["", "./a_k=60.wav", "hi", "61"],
+        ];
+        sample_csv.header_ranges = std::iter::once(0..2).collect();
+        let sample_paths = vec_str!["./a_k=60.wav"];
+        expand_sample_csv(
+            &mut sample_csv,
+            &sample_paths,
+            &rows_vars,
+            &Project::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            render_sfz(&sample_csv),
+            "<region> sample=./a_k=60.wav lokey=0 \n<region> sample=./a_k=60.wav lokey=61 \n"
+        );
+    }
+
+    #[test]
+    fn test_merge_cells() {
+        let rows = [
+            vec_str!["a", "b", "c", "d", "e"],
+            vec_str!["~", "", "=", "+=x", "+=x"],
+            vec_str!["", "~", "+=y", "+=y", "f"],
+            vec_str!["+=z", "", "", "", "\\~"],
+        ];
+        let mut merged = rows[0].clone();
+        rows[1..]
+            .iter()
+            .for_each(|row| merge_row(row.clone(), &mut merged));
+        finish_row(&mut merged);
+        assert_eq!(merged, ["z", "", "c y", "d x y", "~"]);
+
+        // Partial merges of later rows give the same result.
+        let mut partial = rows[1].clone();
+        rows[2..]
+            .iter()
+            .for_each(|row| merge_row(row.clone(), &mut partial));
+        let mut merged = rows[0].clone();
+        merge_row(partial.clone(), &mut merged);
+        finish_row(&mut merged);
+        assert_eq!(merged, ["z", "", "c y", "d x y", "~"]);
+
+        finish_row(&mut partial);
+        assert_eq!(partial, ["z", "", "y", "x y", "~"]);
+    }
+
+    #[test]
+    fn test_merge_rows() {
+        let partials = vec![
+            vec![
+                ("./k10.wav", vec_str!["<region>", "./k10.wav", "1"]),
+                ("./k9.wav", vec_str!["<region>", "./k9.wav", "1"]),
+            ],
+            vec![("./k10.wav", vec_str!["", "./k10.wav", "2"])],
+            vec![("./k10.wav", vec_str!["", "./k10.wav", "3"])],
+            vec![("./k9.wav", vec_str!["", "./k9.wav", "~"])],
+        ]
+        .into_iter()
+        .map(|partial| {
+            partial
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect::<HashMap<_, _>>()
+        })
+        .collect::<Vec<_>>();
+        let options = Options {
+            warn_overrides: true,
+            ..Default::default()
+        };
+        for _ in 0..8 {
+            let rows = merge_rows(
+                partials.clone(),
+                1,
+                (Path::new("test.csv"), &[1, 2, 3, 4]),
+                &options,
+            );
+            assert_eq!(
+                rows,
+                [
+                    vec_str!["<region>", "./k9.wav", ""],
+                    vec_str!["<region>", "./k10.wav", "3"]
+                ]
+            );
+        }
+    }
 }